@@ -82,15 +82,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
       (@arg input: -k --key +takes_value +required #{1,2} {check_file} "The file to read the key from")
       (@arg psbt: -p --psbt +takes_value  #{1,2} {check_file} "The file containing the PSBT to Sign")
       (@arg out: -o --output +takes_value  #{1,2} {check_file_not} "The file to save the resulting PSBT")
+      (@arg sighash: --sighash +takes_value #{1,2} "The sighash type, e.g. all (default), none|anyonecanpay")
      )
      (@subcommand new =>
       (about: "Get a new xpriv")
       (@arg network: -n --network +takes_value +required #{1,2}  "One of: signet, testnet, regtest, bitcoin")
       (@arg out: -o --output +takes_value +required #{1,2} {check_file_not} "The file to save the resulting key")
+      (@arg mnemonic: -m --mnemonic +takes_value #{1,2} {check_file_not} "Derive the key from a new BIP39 mnemonic, saved to this file")
+      (@arg format: -f --format +takes_value #{1,2} "One of: raw (default), base58")
+     )
+     (@subcommand bulk =>
+      (about: "Get several new xprivs for a test wallet, printing each xpub")
+      (@arg network: -n --network +takes_value +required #{1,2}  "One of: signet, testnet, regtest, bitcoin")
+      (@arg count: -c --count +takes_value +required #{1,2} "How many keys to generate")
+      (@arg out: -o --output +takes_value +required #{1,2} {check_file_not} "The new directory to save the keys to, as key_0, key_1, ...")
+     )
+     (@subcommand restore =>
+      (about: "Get the xpriv for a BIP39 mnemonic")
+      (@arg mnemonic: -m --mnemonic +takes_value +required #{1,2} {check_file} "The file to read the mnemonic from")
+      (@arg passphrase: --passphrase +takes_value #{1,2} "The BIP39 passphrase, if any")
+      (@arg network: -n --network +takes_value +required #{1,2}  "One of: signet, testnet, regtest, bitcoin")
+      (@arg out: -o --output +takes_value +required #{1,2} {check_file_not} "The file to save the resulting key")
+      (@arg format: -f --format +takes_value #{1,2} "One of: raw (default), base58")
+     )
+     (@subcommand describe =>
+      (about: "Print the keys and key origins of every input of a PSBT as JSON")
+      (@arg psbt: -p --psbt +takes_value #{1,2} "The base64 PSBT to describe, read from stdin if absent")
      )
      (@subcommand show =>
       (about: "Show xpub for file")
       (@arg input: -i --input +takes_value +required #{1,2} {check_file} "The file to read the key from")
+      (@arg descriptor: -d --descriptor +takes_value #{1,2} "Show an output descriptor instead, one of: tr, wpkh")
+      (@arg path: --path +takes_value #{1,2} "The account path for the descriptor, e.g. m/86'/0'/0' (default m)")
      )
     )
     (@subcommand studio =>
@@ -257,10 +280,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 let psbt_str = args.value_of("psbt");
                 let output = args.value_of_os("out");
 
-                let buf = tokio::fs::read(input).await?;
-                let xpriv = sapio_psbt::SigningKey::read_key_from_buf(&buf[..])?;
+                let xpriv = sapio_psbt::SigningKey::read_key_from_file(input).await?;
                 let psbt = get_psbt_from(psbt_str).await?;
-                let hash_ty = bitcoin::util::sighash::SchnorrSighashType::All;
+                let hash_ty = match args.value_of("sighash") {
+                    Some(s) => sapio_psbt::parse_sighash_type(s)?,
+                    None => bitcoin::util::sighash::SchnorrSighashType::All,
+                };
                 let bytes = xpriv.sign(psbt, hash_ty)?;
 
                 if let Some(file_out) = output {
@@ -273,17 +298,69 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 let network = args.value_of("network").unwrap();
                 let network = Network::from_str(network)?;
                 let out = args.value_of_os("out").unwrap();
-                let xpriv = sapio_psbt::SigningKey::new_key(network)?;
-                let pubkey = xpriv.pubkey(&Secp256k1::new());
-                tokio::fs::write(out, &xpriv.0[0].encode()).await?;
+                let xpriv = if let Some(phrase_out) = args.value_of_os("mnemonic") {
+                    let (mnemonic, xpriv) = sapio_psbt::SigningKey::new_mnemonic("", network)?;
+                    tokio::fs::write(phrase_out, mnemonic.to_string()).await?;
+                    xpriv
+                } else {
+                    sapio_psbt::SigningKey::new_key(network)?
+                };
+                let pubkey = xpriv.pubkey(sapio_psbt::global_secp());
+                let format = args
+                    .value_of("format")
+                    .map(sapio_psbt::KeyFormat::from_str)
+                    .transpose()?
+                    .unwrap_or_default();
+                tokio::fs::write(out, xpriv.encode_as(format)).await?;
                 println!("{}", pubkey[0]);
             }
+            Some(("bulk", args)) => {
+                let network = args.value_of("network").unwrap();
+                let network = Network::from_str(network)?;
+                let count: usize = args.value_of("count").unwrap().parse()?;
+                let out = args.value_of_os("out").unwrap();
+                for xpub in sapio_psbt::SigningKey::new_keys(network, count, out).await? {
+                    println!("{}", xpub);
+                }
+            }
+            Some(("restore", args)) => {
+                let network = args.value_of("network").unwrap();
+                let network = Network::from_str(network)?;
+                let phrase =
+                    tokio::fs::read_to_string(args.value_of_os("mnemonic").unwrap()).await?;
+                let passphrase = args.value_of("passphrase").unwrap_or("");
+                let out = args.value_of_os("out").unwrap();
+                let xpriv =
+                    sapio_psbt::SigningKey::from_mnemonic(phrase.trim(), passphrase, network)?;
+                let pubkey = xpriv.pubkey(sapio_psbt::global_secp());
+                let format = args
+                    .value_of("format")
+                    .map(sapio_psbt::KeyFormat::from_str)
+                    .transpose()?
+                    .unwrap_or_default();
+                tokio::fs::write(out, xpriv.encode_as(format)).await?;
+                println!("{}", pubkey[0]);
+            }
+            Some(("describe", args)) => {
+                let psbt = get_psbt_from(args.value_of("psbt")).await?;
+                let description = sapio_psbt::describe_psbt(&psbt);
+                println!("{}", serde_json::to_string_pretty(&description)?);
+            }
             Some(("show", args)) => {
                 let input = args.value_of_os("input").unwrap();
-                let buf = tokio::fs::read(input).await?;
-                let xpriv = sapio_psbt::SigningKey::read_key_from_buf(&buf[..])?;
-                let pubkey = xpriv.pubkey(&Secp256k1::new());
-                println!("{}", pubkey[0]);
+                let xpriv = sapio_psbt::SigningKey::read_key_from_file(input).await?;
+                let secp = sapio_psbt::global_secp();
+                if let Some(template) = args.value_of("descriptor") {
+                    let template = sapio_psbt::DescriptorTemplate::from_str(template)?;
+                    let path = args
+                        .value_of("path")
+                        .map(bitcoin::util::bip32::DerivationPath::from_str)
+                        .transpose()?
+                        .unwrap_or_else(bitcoin::util::bip32::DerivationPath::master);
+                    println!("{}", xpriv.descriptor(secp, &path, template)?);
+                } else {
+                    println!("{}", xpriv.pubkey(secp)[0]);
+                }
             }
             _ => unreachable!(),
         },
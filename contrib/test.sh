@@ -21,6 +21,8 @@ cargo test --release --all
 if [ "$DO_FEATURE_MATRIX" = true ]; then
     cargo build --all
     cargo test --all
+    # sapio-psbt without filesystem, tokio, or OS randomness
+    cargo test -p sapio-psbt --no-default-features
 fi
 
 # Docs
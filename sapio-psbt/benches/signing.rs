@@ -0,0 +1,126 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Times signing a 100 input taproot PSBT one input at a time, as a batch
+//! sharing one sighash cache, as a batch with constant time key matching, as a
+//! batch reusing derived keys from a warm cache, and (with the `parallel`
+//! feature) on a thread pool. Then times verifying the signatures of a 50
+//! input PSBT one by one and (with `parallel`) on a thread pool. Run with
+//! `cargo bench -p sapio-psbt`.
+use bitcoin::psbt::PartiallySignedTransaction;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey, ExtendedPubKey};
+use bitcoin::{Network, OutPoint, SchnorrSighashType, Script, Transaction, TxIn, TxOut};
+#[cfg(feature = "parallel")]
+use sapio_psbt::verify_psbt_parallel;
+use sapio_psbt::{verify_psbt, DerivationCache, SigningKey, SigningOptions};
+use std::str::FromStr;
+use std::time::Instant;
+
+const INPUTS: u32 = 100;
+const VERIFY_INPUTS: u32 = 50;
+const ROUNDS: u32 = 10;
+
+fn psbt(key: &SigningKey, inputs: u32) -> PartiallySignedTransaction {
+    let secp = Secp256k1::new();
+    let tx = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: (0..inputs)
+            .map(|vout| TxIn {
+                previous_output: OutPoint {
+                    vout,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .collect(),
+        output: vec![TxOut {
+            value: 1000,
+            script_pubkey: Script::new(),
+        }],
+    };
+    let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+    let master = ExtendedPubKey::from_priv(&secp, &key.0[0]);
+    for (i, input) in psbt.inputs.iter_mut().enumerate() {
+        let path = DerivationPath::from_str(&format!("m/{}", i)).unwrap();
+        let internal = master.derive_pub(&secp, &path).unwrap().to_x_only_pub();
+        let spk = Script::new_v1_p2tr(&secp, internal, None);
+        input.witness_utxo = Some(TxOut {
+            value: 10_000,
+            script_pubkey: spk,
+        });
+        input.tap_internal_key = Some(internal);
+        input
+            .tap_key_origins
+            .insert(internal, (vec![], (master.fingerprint(), path)));
+    }
+    psbt
+}
+
+fn time(name: &str, mut f: impl FnMut()) {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        f();
+    }
+    println!("{:>10}: {:?} per psbt", name, start.elapsed() / ROUNDS);
+}
+
+fn main() {
+    let secp = Secp256k1::new();
+    let key = SigningKey(vec![ExtendedPrivKey::new_master(
+        Network::Regtest,
+        &[7; 32],
+    )
+    .unwrap()]);
+    let unsigned = psbt(&key, INPUTS);
+    let hash_ty = SchnorrSighashType::All;
+    let opts = SigningOptions::default();
+    time("per input", || {
+        let mut psbt = unsigned.clone();
+        for idx in 0..psbt.inputs.len() {
+            key.sign_psbt_input_with_options_mut(&mut psbt, &secp, idx, hash_ty, &opts)
+                .unwrap();
+        }
+    });
+    time("batch", || {
+        let mut psbt = unsigned.clone();
+        key.sign_psbt_all_with_options_mut(&mut psbt, &secp, hash_ty, &opts)
+            .unwrap();
+    });
+    let constant_time = SigningOptions {
+        constant_time_matching: true,
+        ..Default::default()
+    };
+    time("ct batch", || {
+        let mut psbt = unsigned.clone();
+        key.sign_psbt_all_with_options_mut(&mut psbt, &secp, hash_ty, &constant_time)
+            .unwrap();
+    });
+    let cached = SigningOptions {
+        derivation_cache: Some(std::sync::Arc::new(DerivationCache::new(INPUTS as usize))),
+        ..Default::default()
+    };
+    time("cached", || {
+        let mut psbt = unsigned.clone();
+        key.sign_psbt_all_with_options_mut(&mut psbt, &secp, hash_ty, &cached)
+            .unwrap();
+    });
+    #[cfg(feature = "parallel")]
+    time("parallel", || {
+        let mut psbt = unsigned.clone();
+        key.sign_psbt_all_parallel(&mut psbt, &secp, hash_ty, &opts)
+            .unwrap();
+    });
+    let mut signed = psbt(&key, VERIFY_INPUTS);
+    key.sign_psbt_all_with_options_mut(&mut signed, &secp, hash_ty, &opts)
+        .unwrap();
+    time("verify", || verify_psbt(&signed, &secp).unwrap());
+    #[cfg(feature = "parallel")]
+    time("verify parallel", || {
+        verify_psbt_parallel(&signed, &secp).unwrap()
+    });
+}
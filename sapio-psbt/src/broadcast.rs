@@ -0,0 +1,154 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Signing, finalizing, and submitting a PSBT in one step, for command line
+//! tools which own every key a spend needs.
+use super::*;
+use bitcoin::Txid;
+use std::future::Future;
+use std::pin::Pin;
+
+/// The part of a bitcoind RPC client needed to broadcast, so callers can use
+/// whichever client they already have and tests can use a mock
+pub trait BitcoindRpc {
+    type Error: std::fmt::Debug;
+    /// `sendrawtransaction` with the consensus serialized transaction in hex
+    fn send_raw_transaction<'a>(
+        &'a self,
+        hex: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Txid, Self::Error>> + Send + 'a>>;
+}
+
+/// Errors from [`SigningKey::sign_finalize_broadcast`], by the stage which
+/// failed
+#[derive(Debug, Clone)]
+pub enum BroadcastError<E> {
+    Signing(PSBTSigningError),
+    Finalize(FinalizeError),
+    /// the node rejected the transaction, or could not be reached
+    Rpc(E),
+}
+
+impl<E: std::fmt::Debug> Display for BroadcastError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl<E: std::fmt::Debug> Error for BroadcastError<E> {}
+
+impl SigningKey {
+    /// Sign every input, finalize with [`SigningKey::finalize_and_extract`],
+    /// and submit the transaction through `rpc`, returning its txid. The
+    /// signatures are left in `psbt` so a failed finalize or broadcast can be
+    /// retried without signing again.
+    ///
+    /// Signing happens before the first await, so dropping the future leaves
+    /// `psbt` either untouched or fully signed, never part signed.
+    pub async fn sign_finalize_broadcast<C: Signing + Verification, R: BitcoindRpc>(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        secp: &Secp256k1<C>,
+        hash_ty: bitcoin::SchnorrSighashType,
+        rpc: &R,
+    ) -> Result<Txid, BroadcastError<R::Error>> {
+        self.sign_psbt_all_mut(psbt, secp, hash_ty)
+            .map_err(BroadcastError::Signing)?;
+        let tx = self
+            .finalize_and_extract(psbt.clone())
+            .map_err(BroadcastError::Finalize)?;
+        let hex = bitcoin::consensus::encode::serialize_hex(&tx);
+        rpc.send_raw_transaction(&hex)
+            .await
+            .map_err(BroadcastError::Rpc)
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+    use bitcoin::hashes::hex::FromHex;
+    use std::sync::Mutex;
+
+    /// records what it is sent, rejecting everything once `reject` is set
+    #[derive(Default)]
+    struct MockRpc {
+        sent: Mutex<Vec<String>>,
+        reject: bool,
+    }
+    impl BitcoindRpc for MockRpc {
+        type Error = String;
+        fn send_raw_transaction<'a>(
+            &'a self,
+            hex: &'a str,
+        ) -> Pin<Box<dyn Future<Output = Result<Txid, String>> + Send + 'a>> {
+            Box::pin(async move {
+                if self.reject {
+                    return Err("txn-mempool-conflict".into());
+                }
+                self.sent.lock().unwrap().push(hex.into());
+                let bytes = Vec::<u8>::from_hex(hex).unwrap();
+                let tx: bitcoin::Transaction = bitcoin::consensus::deserialize(&bytes).unwrap();
+                Ok(tx.txid())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn broadcasts_finalized_tx() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        let mut psbt = unsigned_psbt(2);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &ours, "m/86/0"), &[]);
+        psbt.inputs[1] = wpkh_input(&secp, &ours, "m/84/0").0;
+
+        let mut signed = psbt.clone();
+        ours.sign_psbt_all_mut(&mut signed, &secp, hash_ty).unwrap();
+        let expected = ours.finalize_and_extract(signed).unwrap();
+
+        let rpc = MockRpc::default();
+        let txid = ours
+            .sign_finalize_broadcast(&mut psbt.clone(), &secp, hash_ty, &rpc)
+            .await
+            .unwrap();
+        assert_eq!(txid, expected.txid());
+        assert_eq!(
+            *rpc.sent.lock().unwrap(),
+            vec![bitcoin::consensus::encode::serialize_hex(&expected)]
+        );
+
+        let rejecting = MockRpc {
+            reject: true,
+            ..Default::default()
+        };
+        let mut kept = psbt.clone();
+        assert!(matches!(
+            ours.sign_finalize_broadcast(&mut kept, &secp, hash_ty, &rejecting)
+                .await,
+            Err(BroadcastError::Rpc(_))
+        ));
+        assert!(kept.inputs[0].tap_key_sig.is_some());
+
+        // nothing for these keys to sign, so the inputs cannot be finalized
+        assert!(matches!(
+            test_key(2)
+                .sign_finalize_broadcast(&mut psbt, &secp, hash_ty, &rpc)
+                .await,
+            Err(BroadcastError::Finalize(FinalizeError::NotSigned(0)))
+        ));
+
+        psbt.version = 2;
+        assert!(matches!(
+            ours.sign_finalize_broadcast(&mut psbt, &secp, hash_ty, &rpc)
+                .await,
+            Err(BroadcastError::Signing(
+                PSBTSigningError::UnsupportedPsbtVersion(2)
+            ))
+        ));
+        assert_eq!(rpc.sent.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Remembering derived keys across signing calls, for services which sign
+//! many PSBTs along the same derivation paths.
+use super::*;
+use bitcoin::util::bip32::ChainCode;
+use std::sync::Mutex;
+
+/// A keypair which is erased when evicted or dropped
+struct CachedKeyPair(KeyPair);
+
+impl Drop for CachedKeyPair {
+    fn drop(&mut self) {
+        self.0.non_secure_erase();
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    /// keyed by the chain code of the key derived from, which tells our keys
+    /// apart without computing their fingerprints
+    keys: BTreeMap<(ChainCode, DerivationPath), (CachedKeyPair, u64)>,
+    /// incremented on every lookup, the least recently used entry has the
+    /// lowest tick
+    tick: u64,
+}
+
+/// A least recently used cache of the keypairs derived from our keys, see
+/// [`SigningOptions::derivation_cache`]. Derivation is deterministic so
+/// entries never go stale, they are only evicted to stay within `capacity`.
+/// Eviction scans every entry, so keep `capacity` in the thousands at most.
+pub struct DerivationCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl std::fmt::Debug for DerivationCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DerivationCache")
+            .field("capacity", &self.capacity)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl DerivationCache {
+    pub fn new(capacity: usize) -> Self {
+        DerivationCache {
+            capacity,
+            entries: Default::default(),
+        }
+    }
+
+    /// the number of keypairs held
+    pub fn len(&self) -> usize {
+        self.lock().keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        // a panic elsewhere cannot leave a half written entry
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The keypair `path` below `key`, derived on a miss. The caller should
+    /// erase the returned copy once done with it.
+    pub(crate) fn derive<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        key: &ExtendedPrivKey,
+        path: &DerivationPath,
+    ) -> Result<KeyPair, bitcoin::util::bip32::Error> {
+        let id = (key.chain_code, path.clone());
+        let mut entries = self.lock();
+        entries.tick += 1;
+        let tick = entries.tick;
+        if let Some((kp, used)) = entries.keys.get_mut(&id) {
+            *used = tick;
+            return Ok(kp.0);
+        }
+        let kp = derive_keypair(secp, key, path)?;
+        if self.capacity == 0 {
+            return Ok(kp);
+        }
+        if entries.keys.len() >= self.capacity {
+            let oldest = entries
+                .keys
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                entries.keys.remove(&oldest);
+            }
+        }
+        entries.keys.insert(id, (CachedKeyPair(kp), tick));
+        Ok(kp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn cached_equals_fresh() {
+        let secp = Secp256k1::new();
+        let key = &test_key(1).0[0];
+        let cache = DerivationCache::new(2);
+        let paths: Vec<_> = ["m/0", "m/1", "m/0'/7"]
+            .iter()
+            .map(|p| DerivationPath::from_str(p).unwrap())
+            .collect();
+        for _ in 0..2 {
+            for path in &paths {
+                assert_eq!(
+                    cache.derive(&secp, key, path).unwrap(),
+                    derive_keypair(&secp, key, path).unwrap()
+                );
+                assert!(cache.len() <= 2);
+            }
+        }
+        // the least recently used path was evicted
+        let ids: Vec<_> = cache.lock().keys.keys().map(|(_, p)| p.clone()).collect();
+        assert_eq!(ids, vec![paths[1].clone(), paths[2].clone()]);
+
+        // caching through the signer gives the same signatures
+        let ours = test_key(1);
+        let (pk, origin) = derive(&secp, &ours, "m/1");
+        let mut psbt = unsigned_psbt(2);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &ours, "m/0"), &[(pk, origin)]);
+        psbt.inputs[1] = wpkh_input(&secp, &ours, "m/84/0").0;
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        let mut expected = psbt.clone();
+        ours.sign_psbt_all_mut(&mut expected, &secp, hash_ty)
+            .unwrap();
+        let opts = SigningOptions {
+            derivation_cache: Some(std::sync::Arc::new(DerivationCache::new(16))),
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            let mut cached = psbt.clone();
+            ours.sign_psbt_all_with_options_mut(&mut cached, &secp, hash_ty, &opts)
+                .unwrap();
+            assert_eq!(cached, expected);
+        }
+        assert_eq!(opts.derivation_cache.unwrap().len(), 3);
+    }
+}
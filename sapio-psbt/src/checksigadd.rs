@@ -0,0 +1,290 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Tapscript k-of-n multisigs of the form
+//! `<pk_1> OP_CHECKSIG <pk_2> OP_CHECKSIGADD ... <pk_n> OP_CHECKSIGADD <k> OP_NUMEQUAL`,
+//! as sapio emits them.
+//!
+//! `tap_script_sigs` only records which keys have signed, but the witness
+//! must hold exactly one slot per key, in reverse key order, with an empty
+//! slot for each key which does not sign. [`CheckSigAddLeaf`] tracks each
+//! participant's slot so the signatures can be put in place once enough
+//! cosigners have signed.
+use super::*;
+use crate::finalize::{clear_signing_fields, tap_script};
+use bitcoin::blockdata::opcodes::all::{
+    OP_CHECKSIG, OP_CHECKSIGADD, OP_NUMEQUAL, OP_PUSHNUM_1, OP_PUSHNUM_16,
+};
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::util::taproot::LeafVersion;
+use bitcoin::Witness;
+
+/// Errors from [`CheckSigAddLeaf::new`]
+#[derive(Debug, Clone)]
+pub enum CheckSigAddError {
+    /// the script is not a CHECKSIGADD multisig
+    NotCheckSigAdd(Script),
+    /// the participants given are not the script's keys, in script order
+    ParticipantsMismatch {
+        script: Vec<XOnlyPublicKey>,
+        given: Vec<XOnlyPublicKey>,
+    },
+}
+
+impl Display for CheckSigAddError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for CheckSigAddError {}
+
+/// A CHECKSIGADD multisig leaf and its participants in script order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckSigAddLeaf {
+    script: Script,
+    participants: Vec<XOnlyPublicKey>,
+    threshold: usize,
+}
+
+impl CheckSigAddLeaf {
+    /// Check that `script` is a CHECKSIGADD multisig over exactly
+    /// `participants`, in order
+    pub fn new(
+        script: Script,
+        participants: Vec<XOnlyPublicKey>,
+    ) -> Result<Self, CheckSigAddError> {
+        let (keys, threshold) =
+            parse(&script).ok_or_else(|| CheckSigAddError::NotCheckSigAdd(script.clone()))?;
+        if keys != participants {
+            return Err(CheckSigAddError::ParticipantsMismatch {
+                script: keys,
+                given: participants,
+            });
+        }
+        Ok(CheckSigAddLeaf {
+            script,
+            participants,
+            threshold,
+        })
+    }
+
+    pub fn leaf_hash(&self) -> TapLeafHash {
+        TapLeafHash::from_script(&self.script, LeafVersion::TapScript)
+    }
+
+    /// the number of signatures the leaf needs, its `k`
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    /// Where `key`'s signature goes in the witness stack, counting from the
+    /// bottom. The first key is checked first, so its signature is on top.
+    pub fn witness_position(&self, key: &XOnlyPublicKey) -> Option<usize> {
+        let i = self.participants.iter().position(|p| p == key)?;
+        Some(self.participants.len() - 1 - i)
+    }
+
+    /// The witness spending input `idx` through this leaf: a signature or an
+    /// empty placeholder for every participant, then the script and its
+    /// control block. If more than `threshold` participants have signed,
+    /// only the first `threshold` in script order are used, as
+    /// `OP_NUMEQUAL` needs an exact count.
+    /// The recorded annex, if any, comes last.
+    pub fn witness(
+        &self,
+        psbt: &PartiallySignedTransaction,
+        idx: usize,
+    ) -> Result<Witness, FinalizeError> {
+        let input = psbt
+            .inputs
+            .get(idx)
+            .ok_or(FinalizeError::NoInputAtIndex(idx))?;
+        let leaf = self.leaf_hash();
+        let mut used = 0;
+        let mut stack: Vec<Vec<u8>> = self
+            .participants
+            .iter()
+            .map(|pk| match input.tap_script_sigs.get(&(*pk, leaf)) {
+                Some(sig) if used < self.threshold => {
+                    used += 1;
+                    sig.to_vec()
+                }
+                _ => vec![],
+            })
+            .collect();
+        if used < self.threshold {
+            return Err(FinalizeError::NotEnoughSignatures {
+                index: idx,
+                leaf,
+                remaining: self.threshold - used,
+            });
+        }
+        stack.reverse();
+        let (cb, script) = tap_script(idx, input, leaf)?;
+        stack.push(script.to_bytes());
+        stack.push(cb.serialize());
+        crate::finalize::taproot_witness(idx, input, stack)
+    }
+
+    /// Set input `idx`'s final witness to [`CheckSigAddLeaf::witness`],
+    /// clearing the fields only a finalizer needs
+    pub fn finalize_input(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        idx: usize,
+    ) -> Result<(), FinalizeError> {
+        let witness = self.witness(psbt, idx)?;
+        let input = &mut psbt.inputs[idx];
+        input.final_script_witness = Some(witness);
+        clear_signing_fields(input);
+        Ok(())
+    }
+}
+
+/// The keys and threshold of a CHECKSIGADD multisig
+pub(crate) fn parse(script: &Script) -> Option<(Vec<XOnlyPublicKey>, usize)> {
+    let ins = script.instructions().collect::<Result<Vec<_>, _>>().ok()?;
+    let (tail, body) = match ins.len().checked_sub(2) {
+        Some(n) if n >= 2 && n % 2 == 0 => (&ins[n..], &ins[..n]),
+        _ => return None,
+    };
+    let mut keys = vec![];
+    for (i, pair) in body.chunks(2).enumerate() {
+        let expected = if i == 0 { OP_CHECKSIG } else { OP_CHECKSIGADD };
+        match pair {
+            [Instruction::PushBytes(pk), Instruction::Op(op)] if *op == expected => {
+                keys.push(XOnlyPublicKey::from_slice(pk).ok()?)
+            }
+            _ => return None,
+        }
+    }
+    let threshold = match tail {
+        [Instruction::Op(op), Instruction::Op(OP_NUMEQUAL)]
+            if (OP_PUSHNUM_1.into_u8()..=OP_PUSHNUM_16.into_u8()).contains(&op.into_u8()) =>
+        {
+            (op.into_u8() - OP_PUSHNUM_1.into_u8() + 1) as usize
+        }
+        [Instruction::PushBytes(n), Instruction::Op(OP_NUMEQUAL)] => {
+            usize::try_from(bitcoin::blockdata::script::read_scriptint(n).ok()?).ok()?
+        }
+        _ => return None,
+    };
+    (threshold >= 1 && threshold <= keys.len()).then(|| (keys, threshold))
+}
+
+impl SigningKey {
+    /// Sign input `idx` for `leaf` only, returning the witness positions
+    /// (see [`CheckSigAddLeaf::witness_position`]) of the signatures added.
+    /// Once enough cosigners have signed, [`CheckSigAddLeaf::finalize_input`]
+    /// puts every signature in its slot.
+    pub fn sign_checksigadd_mut<C: Signing + Verification>(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        secp: &Secp256k1<C>,
+        idx: usize,
+        leaf: &CheckSigAddLeaf,
+        hash_ty: bitcoin::SchnorrSighashType,
+    ) -> Result<Vec<usize>, PSBTSigningError> {
+        let hash = leaf.leaf_hash();
+        let before = psbt
+            .inputs
+            .get(idx)
+            .ok_or(PSBTSigningError::NoInputAtIndex(idx))?
+            .tap_script_sigs
+            .clone();
+        self.sign_psbt_input_leaves_mut(psbt, secp, idx, hash_ty, &[hash])?;
+        let after = &psbt.inputs[idx].tap_script_sigs;
+        let mut positions: Vec<_> = leaf
+            .participants
+            .iter()
+            .filter(|pk| {
+                let slot = (**pk, hash);
+                after.get(&slot).is_some() && after.get(&slot) != before.get(&slot)
+            })
+            .filter_map(|pk| leaf.witness_position(pk))
+            .collect();
+        positions.sort_unstable();
+        Ok(positions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Leaf;
+    use crate::tests::*;
+
+    #[test]
+    fn two_of_three_witness_order() {
+        let secp = Secp256k1::new();
+        let signers: Vec<_> = (1..=3)
+            .map(|i| derive(&secp, &test_key(i), "m/0"))
+            .collect();
+        let keys: Vec<_> = signers.iter().map(|(pk, _)| *pk).collect();
+        let script = checksigadd_script(&keys, 2);
+        assert!(matches!(
+            CheckSigAddLeaf::new(script.clone(), vec![keys[1], keys[0], keys[2]]),
+            Err(CheckSigAddError::ParticipantsMismatch { .. })
+        ));
+        assert!(matches!(
+            CheckSigAddLeaf::new(checksig_script(&keys[0]), vec![keys[0]]),
+            Err(CheckSigAddError::NotCheckSigAdd(_))
+        ));
+        let leaf = CheckSigAddLeaf::new(script.clone(), keys.clone()).unwrap();
+        assert_eq!(leaf.threshold(), 2);
+
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] = crate::fixtures::taproot_input(
+            &secp,
+            &derive(&secp, &test_key(4), "m/0"),
+            &[Leaf {
+                weight: 1,
+                script: script.clone(),
+                version: LeafVersion::TapScript,
+                signers,
+            }],
+            10_000,
+        );
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        let first = test_key(3)
+            .sign_checksigadd_mut(&mut psbt, &secp, 0, &leaf, hash_ty)
+            .unwrap();
+        assert_eq!(first, vec![0]);
+        assert!(matches!(
+            leaf.witness(&psbt, 0),
+            Err(FinalizeError::NotEnoughSignatures { remaining: 1, .. })
+        ));
+        let second = test_key(1)
+            .sign_checksigadd_mut(&mut psbt, &secp, 0, &leaf, hash_ty)
+            .unwrap();
+        assert_eq!(second, vec![2]);
+
+        let mut finalized = psbt.clone();
+        leaf.finalize_input(&mut finalized, 0).unwrap();
+        let tx = finalized.extract_tx();
+        let witness = tx.input[0].witness.to_vec();
+        assert_eq!(witness.len(), 5);
+        assert!(witness[1].is_empty());
+        assert_eq!(witness[3], script.to_bytes());
+
+        let utxos = [psbt.inputs[0].witness_utxo.clone().unwrap()];
+        let sighash = bitcoin::util::sighash::SighashCache::new(&tx)
+            .taproot_script_spend_signature_hash(
+                0,
+                &Prevouts::All(&utxos),
+                bitcoin::util::sighash::ScriptPath::with_defaults(&script),
+                hash_ty,
+            )
+            .unwrap();
+        let msg = bitcoin::secp256k1::Message::from_digest_slice(&sighash[..]).unwrap();
+        // key 3 is checked last, so its signature is at the bottom
+        for (position, key) in [(0, keys[2]), (2, keys[0])] {
+            assert_eq!(leaf.witness_position(&key), Some(position));
+            let sig = SchnorrSig::from_slice(&witness[position]).unwrap();
+            secp.verify_schnorr(&sig.sig, &msg, &key).unwrap();
+        }
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Summarizing the keys a PSBT asks for, to debug why a key won't sign it.
+use super::*;
+use serde::{Deserialize, Serialize};
+
+/// The key related fields of every input of a PSBT
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PsbtDescription {
+    pub inputs: Vec<InputDescription>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InputDescription {
+    pub index: usize,
+    pub internal_key: Option<XOnlyPublicKey>,
+    pub has_merkle_root: bool,
+    /// whether the internal key is the unspendable BIP-341 point, see
+    /// [`is_nums_key`]
+    pub nums_internal_key: bool,
+    pub tap_key_origins: Vec<TapKeyOrigin>,
+    pub bip32_derivation: Vec<Bip32Origin>,
+}
+
+/// One `tap_key_origins` entry
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TapKeyOrigin {
+    pub key: XOnlyPublicKey,
+    pub fingerprint: Fingerprint,
+    pub path: DerivationPath,
+    pub leaves: Vec<TapLeafHash>,
+}
+
+/// One `bip32_derivation` entry
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Bip32Origin {
+    pub key: bitcoin::secp256k1::PublicKey,
+    pub fingerprint: Fingerprint,
+    pub path: DerivationPath,
+}
+
+/// The x coordinate of BIP-341's `H`, the hash of the generator point, which
+/// has no known discrete log
+pub(crate) const NUMS_H: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+
+/// Whether `key` is BIP-341's provably unspendable point `H`, the usual
+/// internal key of outputs which may only be spent through a script. Keys
+/// made unspendable by tweaking `H` are not detected.
+pub fn is_nums_key(key: &XOnlyPublicKey) -> bool {
+    key.serialize() == NUMS_H
+}
+
+/// Whether input `idx` has [`is_nums_key`] as its `tap_internal_key`, so no
+/// key path signature can ever be made for it. False if there is no input
+/// at `idx`.
+pub fn is_nums_internal_key(psbt: &PartiallySignedTransaction, idx: usize) -> bool {
+    psbt.inputs
+        .get(idx)
+        .and_then(|i| i.tap_internal_key.as_ref())
+        .map_or(false, is_nums_key)
+}
+
+pub fn describe_psbt(psbt: &PartiallySignedTransaction) -> PsbtDescription {
+    let inputs = psbt
+        .inputs
+        .iter()
+        .enumerate()
+        .map(|(index, input)| InputDescription {
+            index,
+            internal_key: input.tap_internal_key,
+            has_merkle_root: input.tap_merkle_root.is_some(),
+            nums_internal_key: input.tap_internal_key.as_ref().map_or(false, is_nums_key),
+            tap_key_origins: input
+                .tap_key_origins
+                .iter()
+                .map(|(key, (leaves, (fingerprint, path)))| TapKeyOrigin {
+                    key: *key,
+                    fingerprint: *fingerprint,
+                    path: path.clone(),
+                    leaves: leaves.clone(),
+                })
+                .collect(),
+            bip32_derivation: input
+                .bip32_derivation
+                .iter()
+                .map(|(key, (fingerprint, path))| Bip32Origin {
+                    key: *key,
+                    fingerprint: *fingerprint,
+                    path: path.clone(),
+                })
+                .collect(),
+        })
+        .collect();
+    PsbtDescription { inputs }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    #[test]
+    fn describe_two_origins() {
+        let secp = Secp256k1::new();
+        let internal = derive(&secp, &test_key(1), "m/0");
+        let leaf = derive(&secp, &test_key(2), "m/7/1");
+        let mut psbt = unsigned_psbt(2);
+        psbt.inputs[0] = taproot_input(&secp, &internal, std::slice::from_ref(&leaf));
+        psbt.inputs[1] = wpkh_input(&secp, &test_key(3), "m/84/0").0;
+
+        let description = describe_psbt(&psbt);
+        let input = &description.inputs[0];
+        assert_eq!(input.internal_key, Some(internal.0));
+        assert!(input.has_merkle_root);
+        let fingerprints: Vec<_> = input
+            .tap_key_origins
+            .iter()
+            .map(|o| o.fingerprint)
+            .collect();
+        assert_eq!(fingerprints.len(), 2);
+        assert!(fingerprints.contains(&(internal.1).0));
+        assert!(fingerprints.contains(&(leaf.1).0));
+        assert_eq!(description.inputs[1].bip32_derivation.len(), 1);
+
+        let json = serde_json::to_value(&description).unwrap();
+        let origin = json["inputs"][0]["tap_key_origins"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|o| o["key"] == serde_json::json!(leaf.0))
+            .unwrap()
+            .clone();
+        assert_eq!(origin["fingerprint"], (leaf.1).0.to_string());
+        assert_eq!(origin["path"], "m/7/1");
+        assert_eq!(origin["leaves"].as_array().unwrap().len(), 1);
+    }
+}
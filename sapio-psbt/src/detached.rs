@@ -0,0 +1,178 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Signatures separated from their PSBT, so an air-gapped signer only has to
+//! send back what it added.
+use super::*;
+use bitcoin::{EcdsaSig, Txid};
+use serde::{Deserialize, Serialize};
+
+/// The signatures [`SigningKey::sign_detached`] added to a PSBT
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DetachedSignatures {
+    /// the txid of the PSBT's unsigned transaction
+    pub txid: Txid,
+    /// only inputs which received a signature are listed
+    pub inputs: Vec<DetachedInput>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DetachedInput {
+    pub index: usize,
+    pub key_sig: Option<SchnorrSig>,
+    pub script_sigs: Vec<DetachedScriptSig>,
+    pub partial_sigs: Vec<(bitcoin::PublicKey, EcdsaSig)>,
+}
+
+/// One `tap_script_sigs` entry
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DetachedScriptSig {
+    pub key: XOnlyPublicKey,
+    pub leaf: TapLeafHash,
+    pub sig: SchnorrSig,
+}
+
+/// Errors from [`apply_detached`]
+#[derive(Debug, Clone)]
+pub enum DetachedError {
+    /// the signatures are for a transaction with this txid instead
+    DifferentTransaction { expected: Txid, found: Txid },
+    /// the signatures are for an input the PSBT does not have
+    NoInputAtIndex(usize),
+}
+
+impl Display for DetachedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for DetachedError {}
+
+impl SigningKey {
+    /// Sign a copy of `psbt` as [`SigningKey::sign_psbt_all_mut`] would,
+    /// returning only the signatures that were added
+    pub fn sign_detached<C: Signing + Verification>(
+        &self,
+        psbt: &PartiallySignedTransaction,
+        secp: &Secp256k1<C>,
+        hash_ty: bitcoin::SchnorrSighashType,
+    ) -> Result<DetachedSignatures, PSBTSigningError> {
+        let mut signed = psbt.clone();
+        let indices = self.sign_psbt_all_mut(&mut signed, secp, hash_ty)?;
+        let inputs = indices
+            .into_iter()
+            .map(|index| {
+                let (before, after) = (&psbt.inputs[index], &signed.inputs[index]);
+                DetachedInput {
+                    index,
+                    key_sig: after.tap_key_sig.filter(|s| before.tap_key_sig != Some(*s)),
+                    script_sigs: after
+                        .tap_script_sigs
+                        .iter()
+                        .filter(|(k, s)| before.tap_script_sigs.get(k) != Some(s))
+                        .map(|(&(key, leaf), &sig)| DetachedScriptSig { key, leaf, sig })
+                        .collect(),
+                    partial_sigs: after
+                        .partial_sigs
+                        .iter()
+                        .filter(|(k, s)| before.partial_sigs.get(k) != Some(s))
+                        .map(|(k, s)| (*k, *s))
+                        .collect(),
+                }
+            })
+            .filter(|d| {
+                d.key_sig.is_some() || !d.script_sigs.is_empty() || !d.partial_sigs.is_empty()
+            })
+            .collect();
+        Ok(DetachedSignatures {
+            txid: psbt.unsigned_tx.txid(),
+            inputs,
+        })
+    }
+}
+
+/// Attach signatures from [`SigningKey::sign_detached`] to `psbt`, replacing
+/// any already there for the same key. On error `psbt` is left unchanged.
+pub fn apply_detached(
+    psbt: &mut PartiallySignedTransaction,
+    sigs: &DetachedSignatures,
+) -> Result<(), DetachedError> {
+    let found = psbt.unsigned_tx.txid();
+    if found != sigs.txid {
+        return Err(DetachedError::DifferentTransaction {
+            expected: sigs.txid,
+            found,
+        });
+    }
+    if let Some(missing) = sigs.inputs.iter().find(|i| i.index >= psbt.inputs.len()) {
+        return Err(DetachedError::NoInputAtIndex(missing.index));
+    }
+    for detached in &sigs.inputs {
+        let input = &mut psbt.inputs[detached.index];
+        if detached.key_sig.is_some() {
+            input.tap_key_sig = detached.key_sig;
+        }
+        for s in &detached.script_sigs {
+            input.tap_script_sigs.insert((s.key, s.leaf), s.sig);
+        }
+        input
+            .partial_sigs
+            .extend(detached.partial_sigs.iter().copied());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    #[test]
+    fn detach_and_apply() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let (pk, origin) = derive(&secp, &ours, "m/1");
+        let mut psbt = unsigned_psbt(3);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &ours, "m/0"), &[(pk, origin)]);
+        psbt.inputs[1] = taproot_input(&secp, &derive(&secp, &test_key(2), "m/0"), &[]);
+        psbt.inputs[2] = wpkh_input(&secp, &ours, "m/84/0").0;
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+
+        let detached = ours.sign_detached(&psbt, &secp, hash_ty).unwrap();
+        assert_eq!(
+            detached.inputs.iter().map(|i| i.index).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        let json = serde_json::to_string(&detached).unwrap();
+        let detached: DetachedSignatures = serde_json::from_str(&json).unwrap();
+
+        let mut direct = psbt.clone();
+        ours.sign_psbt_all_mut(&mut direct, &secp, hash_ty).unwrap();
+        let mut applied = psbt.clone();
+        apply_detached(&mut applied, &detached).unwrap();
+        assert_eq!(applied, direct);
+        // signing again adds nothing new
+        assert!(ours
+            .sign_detached(&applied, &secp, hash_ty)
+            .unwrap()
+            .inputs
+            .is_empty());
+
+        let mut fewer = unsigned_psbt(3);
+        fewer.inputs.truncate(1);
+        fewer.unsigned_tx = psbt.unsigned_tx.clone();
+        assert!(matches!(
+            apply_detached(&mut fewer, &detached),
+            Err(DetachedError::NoInputAtIndex(2))
+        ));
+        assert!(fewer.inputs[0].tap_key_sig.is_none());
+        let mut other = unsigned_psbt(4);
+        assert!(matches!(
+            apply_detached(&mut other, &detached),
+            Err(DetachedError::DifferentTransaction { .. })
+        ));
+    }
+}
@@ -0,0 +1,371 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ECDSA signing for segwit v0 inputs.
+//!
+//! Every signature is made low S, as standardness requires. BIP-340
+//! signatures have no malleable S, so taproot signing needs no equivalent.
+use super::*;
+use bitcoin::util::sighash::SighashCache;
+use bitcoin::{EcdsaSig, EcdsaSighashType, Script};
+
+/// Whether an input should be signed with the taproot logic rather than ECDSA
+pub(crate) fn is_taproot_input(input: &bitcoin::psbt::Input) -> bool {
+    input.tap_internal_key.is_some()
+        || input.tap_merkle_root.is_some()
+        || !input.tap_key_origins.is_empty()
+        || input
+            .witness_utxo
+            .as_ref()
+            .map(|u| u.script_pubkey.is_v1_p2tr())
+            .unwrap_or(false)
+}
+
+/// The segwit v0 program an input spending `spk` pays to, unwrapping a P2SH
+/// `redeem_script` for nested segwit. `None` for P2SH without a matching
+/// witness program `redeem_script`.
+pub(crate) fn witness_program<'a>(
+    input: &'a bitcoin::psbt::Input,
+    spk: &'a Script,
+) -> Option<&'a Script> {
+    if !spk.is_p2sh() {
+        return Some(spk);
+    }
+    input
+        .redeem_script
+        .as_ref()
+        .filter(|rs| rs.is_witness_program() && Script::new_p2sh(&rs.script_hash()) == *spk)
+}
+
+/// The script code a segwit v0 signature by `pk` commits to, if `program` is
+/// a P2WPKH or a P2WSH of the input's `witness_script`
+fn script_code(
+    program: &Script,
+    witness_script: Option<&Script>,
+    pk: &bitcoin::PublicKey,
+) -> Option<Script> {
+    if program.is_v0_p2wpkh() {
+        return Some(Script::new_p2pkh(&pk.pubkey_hash()));
+    }
+    witness_script
+        .filter(|ws| program.is_v0_p2wsh() && Script::new_v0_p2wsh(&ws.wscript_hash()) == *program)
+        .cloned()
+}
+
+/// The ECDSA equivalent of a taproot sighash type. `Default` is the same as
+/// `All`, and `Reserved` has no ECDSA counterpart.
+pub(crate) fn ecdsa_sighash_type(
+    hash_ty: bitcoin::SchnorrSighashType,
+) -> Result<EcdsaSighashType, PSBTSigningError> {
+    use bitcoin::SchnorrSighashType::*;
+    Ok(match hash_ty {
+        Default | All => EcdsaSighashType::All,
+        None => EcdsaSighashType::None,
+        Single => EcdsaSighashType::Single,
+        AllPlusAnyoneCanPay => EcdsaSighashType::AllPlusAnyoneCanPay,
+        NonePlusAnyoneCanPay => EcdsaSighashType::NonePlusAnyoneCanPay,
+        SinglePlusAnyoneCanPay => EcdsaSighashType::SinglePlusAnyoneCanPay,
+        Reserved => return Err(PSBTSigningError::UnsupportedSighashType(hash_ty)),
+    })
+}
+
+/// Whether `sig`'s S is at most half the curve order
+pub(crate) fn is_low_s(sig: &bitcoin::secp256k1::ecdsa::Signature) -> bool {
+    let mut normalized = *sig;
+    normalized.normalize_s();
+    normalized == *sig
+}
+
+/// The only way this module builds an [`EcdsaSig`], so that no signature
+/// with a high S can reach `partial_sigs`
+fn low_s_sig(mut sig: bitcoin::secp256k1::ecdsa::Signature, hash_ty: EcdsaSighashType) -> EcdsaSig {
+    sig.normalize_s();
+    EcdsaSig { sig, hash_ty }
+}
+
+impl SigningKey {
+    /// Signs a segwit v0 input for every key in `bip32_derivation` that this
+    /// key can derive, returning the number of `partial_sigs` added.
+    pub fn sign_ecdsa_input_mut<C: Signing + Verification>(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        secp: &Secp256k1<C>,
+        idx: usize,
+        hash_ty: EcdsaSighashType,
+    ) -> Result<usize, PSBTSigningError> {
+        check_psbt(psbt)?;
+        let tx = psbt.clone().extract_tx();
+        let mut sighash = SighashCache::new(&tx);
+        let input = psbt
+            .inputs
+            .get_mut(idx)
+            .ok_or(PSBTSigningError::NoInputAtIndex(idx))?;
+        let outpoint = &tx.input[idx].previous_output;
+        let opts = SigningOptions::default();
+        self.sign_ecdsa_input_in_context(
+            secp,
+            &mut sighash,
+            &psbt.xpub,
+            idx,
+            outpoint,
+            input,
+            hash_ty,
+            &opts,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn sign_ecdsa_input_in_context<C: Signing + Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        sighash: &mut SighashCache<&bitcoin::Transaction>,
+        xpubs: &BTreeMap<ExtendedPubKey, KeySource>,
+        idx: usize,
+        outpoint: &bitcoin::OutPoint,
+        input: &mut bitcoin::psbt::Input,
+        hash_ty: EcdsaSighashType,
+        opts: &SigningOptions,
+    ) -> Result<usize, PSBTSigningError> {
+        let fingerprints_map = self.compute_fingerprint_map(secp, opts, xpubs);
+        let signers = self
+            .compute_matching_ecdsa_keys(secp, &input.bip32_derivation, &fingerprints_map)
+            .collect::<Result<Vec<_>, _>>()?;
+        if signers.is_empty() {
+            return Ok(0);
+        }
+        let utxo = spent_utxo(idx, input, outpoint)?;
+        let program = witness_program(input, &utxo.script_pubkey).cloned();
+        let mut count = 0;
+        for (pk, mut sk) in signers {
+            let script_code = match program
+                .as_ref()
+                .and_then(|p| script_code(p, input.witness_script.as_ref(), &pk))
+            {
+                Some(code) => code,
+                None => {
+                    sk.non_secure_erase();
+                    return Err(PSBTSigningError::UnsupportedScriptType {
+                        index: idx,
+                        script_pubkey: utxo.script_pubkey.clone(),
+                    });
+                }
+            };
+            let hash = match sighash.segwit_signature_hash(idx, &script_code, utxo.value, hash_ty) {
+                Ok(hash) => hash,
+                Err(e) => {
+                    sk.non_secure_erase();
+                    return Err(PSBTSigningError::Sighash(e));
+                }
+            };
+            let msg = bitcoin::secp256k1::Message::from_digest_slice(&hash[..])
+                .expect("Size must be correct.");
+            let sig = secp.sign_ecdsa(&msg, &sk);
+            sk.non_secure_erase();
+            debug_assert!(is_low_s(&sig), "libsecp256k1 signs with low S");
+            input.partial_sigs.insert(pk, low_s_sig(sig, hash_ty));
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Compute secret keys for all derivations matching our fingerprints
+    pub(crate) fn compute_matching_ecdsa_keys<'a, C: Signing>(
+        &'a self,
+        secp: &'a Secp256k1<C>,
+        derivations: &'a BTreeMap<bitcoin::secp256k1::PublicKey, KeySource>,
+        fingerprints_map: &'a FingerprintMap<'a>,
+    ) -> impl Iterator<
+        Item = Result<(bitcoin::PublicKey, bitcoin::secp256k1::SecretKey), PSBTSigningError>,
+    > + 'a {
+        derivations.iter().filter_map(move |(pk, origin)| {
+            for (key, path) in keys_for_origin(fingerprints_map, origin) {
+                derivation_event!(fingerprint = %origin.0, path = %origin.1, key = %pk, "origin fingerprint is ours");
+                let mut kp = match fingerprints_map.derive(secp, key, &path) {
+                    Ok(kp) => kp,
+                    Err(e) => {
+                        derivation_event!(fingerprint = %origin.0, path = %origin.1, error = %e, "derivation failed");
+                        return Some(Err(PSBTSigningError::Derivation(e)));
+                    }
+                };
+                let mut sk = bitcoin::secp256k1::SecretKey::from_keypair(&kp);
+                kp.non_secure_erase();
+                let derived = sk.public_key(secp);
+                if fingerprints_map.same_key(&derived.serialize(), &pk.serialize()) {
+                    derivation_event!(fingerprint = %origin.0, path = %origin.1, key = %pk, "derived key matches origin");
+                    return Some(Ok((bitcoin::PublicKey::new(*pk), sk)));
+                }
+                derivation_event!(
+                    fingerprint = %origin.0,
+                    path = %origin.1,
+                    key = %pk,
+                    %derived,
+                    "derived key does not match origin"
+                );
+                sk.non_secure_erase();
+            }
+            derivation_event!(fingerprint = %origin.0, path = %origin.1, key = %pk, "no held key for origin");
+            None
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+    use bitcoin::{OutPoint, Transaction};
+
+    fn verify_partial_sig<C: Verification>(
+        secp: &Secp256k1<C>,
+        psbt: &PartiallySignedTransaction,
+        idx: usize,
+        pk: &bitcoin::PublicKey,
+    ) {
+        let sig = psbt.inputs[idx].partial_sigs[pk];
+        let utxo = &witness_utxos(psbt).unwrap()[idx];
+        let tx = psbt.clone().extract_tx();
+        let hash = SighashCache::new(&tx)
+            .segwit_signature_hash(
+                idx,
+                &Script::new_p2pkh(&pk.pubkey_hash()),
+                utxo.value,
+                sig.hash_ty,
+            )
+            .unwrap();
+        let msg = bitcoin::secp256k1::Message::from_digest_slice(&hash[..]).unwrap();
+        secp.verify_ecdsa(&msg, &sig.sig, &pk.inner).unwrap();
+    }
+
+    #[test]
+    fn sign_v0_only() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let mut psbt = unsigned_psbt(1);
+        let (input, pk) = wpkh_input(&secp, &ours, "m/84/0");
+        psbt.inputs[0] = input;
+        ours.sign_psbt_input_mut(&mut psbt, &secp, 0, bitcoin::SchnorrSighashType::All)
+            .unwrap();
+        assert_eq!(
+            psbt.inputs[0].partial_sigs[&pk].hash_ty,
+            EcdsaSighashType::All
+        );
+        verify_partial_sig(&secp, &psbt, 0, &pk);
+    }
+
+    #[test]
+    fn sign_v0_with_non_witness_utxo() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let mut psbt = unsigned_psbt(1);
+        let (mut input, pk) = wpkh_input(&secp, &ours, "m/84/0");
+        let prev = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![input.witness_utxo.take().unwrap()],
+        };
+        psbt.unsigned_tx.input[0].previous_output = OutPoint::new(prev.txid(), 0);
+        input.non_witness_utxo = Some(prev);
+        psbt.inputs[0] = input;
+        assert_eq!(
+            ours.sign_ecdsa_input_mut(&mut psbt, &secp, 0, EcdsaSighashType::All)
+                .unwrap(),
+            1
+        );
+        verify_partial_sig(&secp, &psbt, 0, &pk);
+    }
+
+    #[test]
+    fn signatures_are_low_s() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let mut psbt = unsigned_psbt(8);
+        let mut keys = vec![];
+        for (i, input) in psbt.inputs.iter_mut().enumerate() {
+            let (wpkh, pk) = wpkh_input(&secp, &ours, &format!("m/84/{}", i));
+            *input = wpkh;
+            keys.push(pk);
+        }
+        ours.sign_psbt_all_mut(&mut psbt, &secp, bitcoin::SchnorrSighashType::All)
+            .unwrap();
+        // half the curve order, the largest low S
+        let half_n: [u8; 32] = [
+            0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0xff, 0x5d, 0x57, 0x6e, 0x73, 0x57, 0xa4, 0x50, 0x1d, 0xdf, 0xe9, 0x2f, 0x46,
+            0x68, 0x1b, 0x20, 0xa0,
+        ];
+        for (i, pk) in keys.iter().enumerate() {
+            let sig = psbt.inputs[i].partial_sigs[pk].sig;
+            assert!(is_low_s(&sig));
+            assert!(sig.serialize_compact()[32..] <= half_n[..]);
+            verify_partial_sig(&secp, &psbt, i, pk);
+        }
+
+        // n - s is the high S twin of a signature, which is never emitted
+        let n: [u8; 32] = [
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0xfe, 0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c,
+            0xd0, 0x36, 0x41, 0x41,
+        ];
+        let low = psbt.inputs[0].partial_sigs[&keys[0]].sig;
+        let mut compact = low.serialize_compact();
+        let mut borrow = 0i16;
+        for j in (0..32).rev() {
+            let d = n[j] as i16 - compact[32 + j] as i16 - borrow;
+            borrow = (d < 0) as i16;
+            compact[32 + j] = d.rem_euclid(256) as u8;
+        }
+        let high = bitcoin::secp256k1::ecdsa::Signature::from_compact(&compact).unwrap();
+        assert!(!is_low_s(&high));
+        assert_eq!(low_s_sig(high, EcdsaSighashType::All).sig, low);
+    }
+
+    #[test]
+    fn sign_nested_p2wpkh() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let mut psbt = unsigned_psbt(1);
+        let (mut input, pk) = wpkh_input(&secp, &ours, "m/49/0");
+        let redeem = Script::new_v0_p2wpkh(&pk.wpubkey_hash().unwrap());
+        input.witness_utxo.as_mut().unwrap().script_pubkey =
+            Script::new_p2sh(&redeem.script_hash());
+        input.redeem_script = Some(redeem.clone());
+        psbt.inputs[0] = input;
+
+        let mut wrong = psbt.clone();
+        let (_, theirs) = wpkh_input(&secp, &test_key(2), "m/49/0");
+        wrong.inputs[0].redeem_script =
+            Some(Script::new_v0_p2wpkh(&theirs.wpubkey_hash().unwrap()));
+        assert!(matches!(
+            ours.sign_psbt_input_mut(&mut wrong, &secp, 0, bitcoin::SchnorrSighashType::All),
+            Err(PSBTSigningError::UnsupportedScriptType { index: 0, .. })
+        ));
+        assert!(wrong.inputs[0].partial_sigs.is_empty());
+
+        ours.sign_psbt_input_mut(&mut psbt, &secp, 0, bitcoin::SchnorrSighashType::All)
+            .unwrap();
+        assert_eq!(psbt.inputs[0].redeem_script, Some(redeem));
+        verify_partial_sig(&secp, &psbt, 0, &pk);
+    }
+
+    #[test]
+    fn sign_mixed_taproot_and_v0() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let mut psbt = unsigned_psbt(2);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &ours, "m/86/0"), &[]);
+        let (input, pk) = wpkh_input(&secp, &ours, "m/84/0");
+        psbt.inputs[1] = input;
+        let signed = ours
+            .sign_psbt_all_mut(&mut psbt, &secp, bitcoin::SchnorrSighashType::Default)
+            .unwrap();
+        assert_eq!(signed, vec![0, 1]);
+        assert!(psbt.inputs[0].tap_key_sig.is_some());
+        assert!(psbt.inputs[0].partial_sigs.is_empty());
+        assert!(psbt.inputs[1].tap_key_sig.is_none());
+        verify_partial_sig(&secp, &psbt, 1, &pk);
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The size and fee of a PSBT's transaction once finalized, known before
+//! it is signed.
+use super::*;
+use bitcoin::util::taproot::ControlBlock;
+use bitcoin::Witness;
+
+/// Errors from [`estimate_finalized_weight`] and [`estimate_fee`]
+#[derive(Debug, Clone)]
+pub enum EstimateError {
+    /// the output spent by an input is not in the PSBT
+    Prevout(PSBTSigningError),
+    /// input `index` has no final witness and spends neither a taproot nor
+    /// a p2wpkh output, so its witness size is unknown
+    UnknownSpend { index: usize },
+    /// the outputs are worth more than the inputs
+    OutputsExceedInputs { inputs: u64, outputs: u64 },
+}
+
+impl Display for EstimateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for EstimateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EstimateError::Prevout(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<PSBTSigningError> for EstimateError {
+    fn from(e: PSBTSigningError) -> Self {
+        EstimateError::Prevout(e)
+    }
+}
+
+/// a DER signature with a high R, plus the sighash byte
+const ECDSA_SIG_LEN: usize = 72;
+
+/// The weight of the transaction once every input is finalized. Inputs with
+/// a final witness count as they are, and the rest as the witness they are
+/// expected to get, with placeholders for signatures not yet made:
+///
+/// - taproot inputs with a key path signature, or no leaves, or no leaf
+///   signatures and a spendable internal key, spend the key path
+/// - other taproot inputs spend the leaf with the most signatures, or if none
+///   are signed yet the leaf with the smallest witness. A CHECKSIGADD leaf
+///   needs its threshold of signatures, and any other leaf one per signer
+///   listed in `tap_key_origins`.
+/// - p2wpkh inputs spend with a signature and compressed key
+///
+/// Signatures are 64 bytes for `SIGHASH_DEFAULT`, or 65 with any other
+/// `sighash_type`. An ECDSA signature may come out a byte shorter than
+/// estimated.
+pub fn estimate_finalized_weight(
+    psbt: &PartiallySignedTransaction,
+) -> Result<usize, EstimateError> {
+    let utxos = witness_utxos(psbt)?;
+    let mut tx = psbt.unsigned_tx.clone();
+    for (index, (txin, input)) in tx.input.iter_mut().zip(&psbt.inputs).enumerate() {
+        if let Some(script_sig) = &input.final_script_sig {
+            txin.script_sig = script_sig.clone();
+        }
+        txin.witness = match &input.final_script_witness {
+            Some(witness) => witness.clone(),
+            None if input.final_script_sig.is_some() => Witness::default(),
+            None => expected_witness(index, input, &utxos[index].script_pubkey)?,
+        };
+    }
+    Ok(tx.weight())
+}
+
+/// The fee paid by the PSBT's transaction: what its inputs spend less what
+/// its outputs pay
+pub fn estimate_fee(psbt: &PartiallySignedTransaction) -> Result<u64, EstimateError> {
+    let inputs = witness_utxos(psbt)?.iter().map(|utxo| utxo.value).sum();
+    let outputs = psbt.unsigned_tx.output.iter().map(|out| out.value).sum();
+    if outputs > inputs {
+        return Err(EstimateError::OutputsExceedInputs { inputs, outputs });
+    }
+    Ok(inputs - outputs)
+}
+
+fn expected_witness(
+    index: usize,
+    input: &bitcoin::psbt::Input,
+    spk: &Script,
+) -> Result<Witness, EstimateError> {
+    let sig_len = match input.sighash_type.map(|t| t.to_u32()) {
+        None | Some(0) => 64,
+        Some(_) => 65,
+    };
+    if spk.is_v0_p2wpkh() {
+        return Ok(Witness::from_vec(vec![vec![0; ECDSA_SIG_LEN], vec![0; 33]]));
+    }
+    if !spk.is_v1_p2tr() {
+        return Err(EstimateError::UnknownSpend { index });
+    }
+    if let Some(sig) = input.tap_key_sig {
+        return Ok(Witness::from_vec(vec![sig.to_vec()]));
+    }
+    let leaf_sigs = |leaf: TapLeafHash| {
+        input
+            .tap_script_sigs
+            .keys()
+            .filter(|(_, l)| *l == leaf)
+            .count()
+    };
+    let leaves = input.tap_scripts.iter().map(|(cb, (script, ver))| {
+        let leaf = TapLeafHash::from_script(script, *ver);
+        (
+            leaf_sigs(leaf),
+            leaf_witness(input, leaf, cb, script, sig_len),
+        )
+    });
+    let signed = leaves
+        .clone()
+        .filter(|(sigs, _)| *sigs > 0)
+        .max_by_key(|(sigs, _)| *sigs);
+    let key_path_spendable = input.tap_internal_key.is_some()
+        && !input.tap_internal_key.as_ref().map_or(false, is_nums_key);
+    let chosen = match signed {
+        Some((_, witness)) => Some(witness),
+        None if key_path_spendable => None,
+        None => leaves.map(|(_, w)| w).min_by_key(|w| w.serialized_len()),
+    };
+    Ok(chosen.unwrap_or_else(|| Witness::from_vec(vec![vec![0; sig_len]])))
+}
+
+/// The witness spending `leaf`, with a placeholder for each signature
+fn leaf_witness(
+    input: &bitcoin::psbt::Input,
+    leaf: TapLeafHash,
+    cb: &ControlBlock,
+    script: &Script,
+    sig_len: usize,
+) -> Witness {
+    let mut stack = match crate::checksigadd::parse(script) {
+        // the threshold signatures, and an empty push for each other key
+        Some((keys, threshold)) => (0..keys.len())
+            .map(|i| vec![0; if i < threshold { sig_len } else { 0 }])
+            .collect(),
+        None => {
+            let signers = input
+                .tap_key_origins
+                .values()
+                .filter(|(leaves, _)| leaves.contains(&leaf))
+                .count();
+            vec![vec![0; sig_len]; signers.max(1)]
+        }
+    };
+    stack.push(script.to_bytes());
+    stack.push(cb.serialize());
+    Witness::from_vec(stack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    #[test]
+    fn estimate_matches_finalized() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let (pk, origin) = derive(&secp, &ours, "m/1");
+        let (other, other_origin) = derive(&secp, &test_key(3), "m/1");
+        let mut psbt = unsigned_psbt(3);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &ours, "m/0"), &[]);
+        // the key path is unspendable, so the cheapest leaf is spent
+        let nums = XOnlyPublicKey::from_slice(&crate::describe::NUMS_H).unwrap();
+        let internal = (nums, derive(&secp, &test_key(2), "m/0").1);
+        psbt.inputs[1] = taproot_input(&secp, &internal, &[(pk, origin), (other, other_origin)]);
+        psbt.inputs[2] = wpkh_input(&secp, &ours, "m/84/0").0;
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        for input in &mut psbt.inputs {
+            input.sighash_type = Some(hash_ty.into());
+        }
+        let estimate = estimate_finalized_weight(&psbt).unwrap();
+        let fee = estimate_fee(&psbt).unwrap();
+
+        ours.sign_psbt_mut(&mut psbt, &secp, hash_ty).unwrap();
+        // once signed, the leaf with our signature is the one spent
+        assert_eq!(estimate_finalized_weight(&psbt).unwrap(), estimate);
+        let inputs: u64 = psbt
+            .inputs
+            .iter()
+            .map(|i| i.witness_utxo.as_ref().unwrap().value)
+            .sum();
+        let tx = ours.finalize_and_extract(psbt.clone()).unwrap();
+        assert_eq!(fee, inputs - tx.output.iter().map(|o| o.value).sum::<u64>());
+        // only the ECDSA signature's length may differ
+        assert!(estimate - tx.weight() <= 1);
+        assert_eq!(tx.input[0].witness.to_vec()[0].len(), 65);
+
+        let mut finalized = psbt.clone();
+        for (input, txin) in finalized.inputs.iter_mut().zip(&tx.input) {
+            input.final_script_witness = Some(txin.witness.clone());
+        }
+        assert_eq!(estimate_finalized_weight(&finalized).unwrap(), tx.weight());
+
+        psbt.inputs[2].witness_utxo = None;
+        assert!(matches!(
+            estimate_finalized_weight(&psbt),
+            Err(EstimateError::Prevout(PSBTSigningError::NoUTXOAtIndex(2)))
+        ));
+        assert!(matches!(
+            estimate_fee(&psbt),
+            Err(EstimateError::Prevout(_))
+        ));
+    }
+}
@@ -11,6 +11,20 @@ use bitcoin::secp256k1::Secp256k1;
 
 use bitcoin::psbt::PartiallySignedTransaction;
 
+use crate::{
+    check_psbt, is_sighash_single, witness_utxos, InputSignatures, PSBTSigningError, SigningKey,
+    DEFAULT_CODESEP,
+};
+use bitcoin::schnorr::{TapTweak, TweakedPublicKey};
+use bitcoin::util::bip32::{Fingerprint, KeySource};
+use bitcoin::util::sighash::{Prevouts, SighashCache};
+use bitcoin::util::taproot::{TapBranchHash, TapLeafHash, TapSighashHash};
+use bitcoin::{SchnorrSig, Script, XOnlyPublicKey};
+use std::error::Error;
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+
 #[derive(Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum PSBTApi {
@@ -27,8 +41,7 @@ pub enum PSBTApi {
 }
 
 pub fn finalize_psbt_format_api(psbt: PartiallySignedTransaction) -> PSBTApi {
-    let secp = Secp256k1::new();
-    psbt.finalize(&secp)
+    psbt.finalize(crate::global_secp())
         .map(|tx| {
             let hex = bitcoin::consensus::encode::serialize_hex(&tx.extract_tx());
             PSBTApi::Finished {
@@ -47,3 +60,375 @@ pub fn finalize_psbt_format_api(psbt: PartiallySignedTransaction) -> PSBTApi {
             }
         })
 }
+
+/// Which spend path an external signature is for, which decides whether the
+/// device must apply the taproot output key tweak
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpendPath {
+    /// sign with the key tweaked by `merkle_root`, as in BIP-341
+    KeyPath { merkle_root: Option<TapBranchHash> },
+    /// sign with the untweaked key, for this leaf
+    ScriptPath(TapLeafHash),
+}
+
+/// A signer holding keys outside of this process, e.g. an HSM or hardware
+/// wallet.
+///
+/// [`sign_psbt_input_external`] does everything but the secp256k1 operation:
+/// it picks out the key origins the device claims, computes each sighash,
+/// asks the device to sign it, checks the returned signature against the key
+/// in the PSBT, and fills in the PSBT fields. A device implementation only
+/// needs to derive the key at `key_origin`, apply the tweak for a
+/// [`SpendPath::KeyPath`], and sign `sighash`, ideally after showing the user
+/// what they are signing.
+pub trait ExternalSigner {
+    type Error: std::fmt::Debug;
+    /// whether this signer holds the master key with fingerprint `f`
+    fn owns_fingerprint(&self, f: Fingerprint) -> bool;
+    /// BIP-340 sign `sighash` with the key at `key_origin`
+    fn sign_taproot<'a>(
+        &'a self,
+        sighash: TapSighashHash,
+        hash_ty: bitcoin::SchnorrSighashType,
+        key_origin: &'a KeySource,
+        path: SpendPath,
+    ) -> Pin<Box<dyn Future<Output = Result<SchnorrSig, Self::Error>> + Send + 'a>>;
+}
+
+/// Errors from [`sign_psbt_input_external`]
+#[derive(Debug, Clone)]
+pub enum ExternalSignError<E> {
+    Signing(PSBTSigningError),
+    /// the signer itself failed
+    Signer(E),
+    /// the signer returned a signature which does not verify for `key`
+    InvalidSignature {
+        key: XOnlyPublicKey,
+    },
+}
+
+impl<E: std::fmt::Debug> Display for ExternalSignError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl<E: std::fmt::Debug> Error for ExternalSignError<E> {}
+impl<E> From<PSBTSigningError> for ExternalSignError<E> {
+    fn from(e: PSBTSigningError) -> Self {
+        ExternalSignError::Signing(e)
+    }
+}
+
+/// Sign taproot input `idx` with `signer`, for every key origin it owns.
+///
+/// The signatures are collected on a copy of the input and written back only
+/// once every one has been made, so dropping the future part way through (or
+/// an error from the signer) leaves `psbt` untouched.
+pub async fn sign_psbt_input_external<S: ExternalSigner>(
+    signer: &S,
+    psbt: &mut PartiallySignedTransaction,
+    idx: usize,
+    hash_ty: bitcoin::SchnorrSighashType,
+) -> Result<InputSignatures, ExternalSignError<S::Error>> {
+    let secp = crate::global_secp();
+    check_psbt(psbt)?;
+    let utxos = witness_utxos(psbt)?;
+    let tx = psbt.clone().extract_tx();
+    let mut sighash = SighashCache::new(&tx);
+    let prevouts = Prevouts::All(&utxos);
+    let mut input = psbt
+        .inputs
+        .get(idx)
+        .cloned()
+        .ok_or(PSBTSigningError::NoInputAtIndex(idx))?;
+    if is_sighash_single(hash_ty) && idx >= tx.output.len() {
+        return Err(PSBTSigningError::SingleWithoutOutput { index: idx }.into());
+    }
+    let mut summary = InputSignatures::default();
+    let origins: Vec<_> = input
+        .tap_key_origins
+        .iter()
+        .filter(|(_, (_, (f, _)))| signer.owns_fingerprint(*f))
+        .map(|(pk, (leaves, origin))| (*pk, leaves.clone(), origin.clone()))
+        .collect();
+    let merkle_root = input.tap_merkle_root;
+    // the device trusts the merkle root it is given, so check it commits to
+    // the output being spent before asking for any signature
+    let output_key = match input.tap_internal_key {
+        Some(internal) if origins.iter().any(|(pk, ..)| *pk == internal) => {
+            let output_key = internal.tap_tweak(secp, merkle_root).0.to_inner();
+            let expected =
+                Script::new_v1_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(output_key));
+            if utxos[idx].script_pubkey != expected {
+                return Err(PSBTSigningError::OutputKeyMismatch {
+                    index: idx,
+                    script_pubkey: utxos[idx].script_pubkey.clone(),
+                    tweaked: output_key,
+                }
+                .into());
+            }
+            Some(output_key)
+        }
+        _ => None,
+    };
+    for (pk, leaves, origin) in origins {
+        if let Some(output_key) = output_key.filter(|_| input.tap_internal_key == Some(pk)) {
+            let hash = sighash
+                .taproot_signature_hash(idx, &prevouts, None, None, hash_ty)
+                .map_err(PSBTSigningError::Sighash)?;
+            let sig = signer
+                .sign_taproot(hash, hash_ty, &origin, SpendPath::KeyPath { merkle_root })
+                .await
+                .map_err(ExternalSignError::Signer)?;
+            check_sig(secp, &hash, &sig, output_key)?;
+            input.tap_key_sig = Some(sig);
+            summary.key_path_signed = true;
+        }
+        for (i, &leaf) in leaves.iter().enumerate() {
+            if leaves[..i].contains(&leaf) {
+                continue;
+            }
+            let hash = sighash
+                .taproot_signature_hash(
+                    idx,
+                    &prevouts,
+                    None,
+                    Some((leaf, DEFAULT_CODESEP)),
+                    hash_ty,
+                )
+                .map_err(PSBTSigningError::Sighash)?;
+            let sig = signer
+                .sign_taproot(hash, hash_ty, &origin, SpendPath::ScriptPath(leaf))
+                .await
+                .map_err(ExternalSignError::Signer)?;
+            check_sig(secp, &hash, &sig, pk)?;
+            input.tap_script_sigs.insert((pk, leaf), sig);
+            summary.script_sigs_added += 1;
+        }
+    }
+    psbt.inputs[idx] = input;
+    Ok(summary)
+}
+
+fn check_sig<C: bitcoin::secp256k1::Verification, E>(
+    secp: &Secp256k1<C>,
+    hash: &TapSighashHash,
+    sig: &SchnorrSig,
+    key: XOnlyPublicKey,
+) -> Result<(), ExternalSignError<E>> {
+    let msg = crate::sighash_message(hash);
+    secp.verify_schnorr(&sig.sig, &msg, &key)
+        .map_err(|_| ExternalSignError::InvalidSignature { key })
+}
+
+/// An in-process [`ExternalSigner`], mostly useful for testing integrations
+impl ExternalSigner for SigningKey {
+    type Error = PSBTSigningError;
+    fn owns_fingerprint(&self, f: Fingerprint) -> bool {
+        let secp = crate::global_secp();
+        self.0.iter().any(|k| k.fingerprint(secp) == f)
+    }
+    fn sign_taproot<'a>(
+        &'a self,
+        sighash: TapSighashHash,
+        hash_ty: bitcoin::SchnorrSighashType,
+        (f, path): &'a KeySource,
+        spend: SpendPath,
+    ) -> Pin<Box<dyn Future<Output = Result<SchnorrSig, PSBTSigningError>> + Send + 'a>> {
+        Box::pin(async move {
+            let secp = crate::global_secp();
+            let key = self
+                .0
+                .iter()
+                .find(|k| k.fingerprint(secp) == *f)
+                .ok_or(PSBTSigningError::UnknownFingerprint(*f))?;
+            let mut kp =
+                crate::derive_keypair(secp, key, path).map_err(PSBTSigningError::Derivation)?;
+            if let SpendPath::KeyPath { merkle_root } = spend {
+                let mut untweaked = kp;
+                kp = untweaked.tap_tweak(secp, merkle_root).into_inner();
+                untweaked.non_secure_erase();
+            }
+            let msg = crate::sighash_message(&sighash);
+            let sig = secp.sign_schnorr_no_aux_rand(&msg, &kp);
+            kp.non_secure_erase();
+            Ok(SchnorrSig { sig, hash_ty })
+        })
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    /// a device which signs with a key other than the one it is asked for
+    struct WrongKeyDevice(SigningKey);
+    impl ExternalSigner for WrongKeyDevice {
+        type Error = PSBTSigningError;
+        fn owns_fingerprint(&self, _: Fingerprint) -> bool {
+            true
+        }
+        fn sign_taproot<'a>(
+            &'a self,
+            sighash: TapSighashHash,
+            hash_ty: bitcoin::SchnorrSighashType,
+            (_, path): &'a KeySource,
+            spend: SpendPath,
+        ) -> Pin<Box<dyn Future<Output = Result<SchnorrSig, PSBTSigningError>> + Send + 'a>>
+        {
+            Box::pin(async move {
+                let origin = (self.0 .0[0].fingerprint(&Secp256k1::new()), path.clone());
+                self.0.sign_taproot(sighash, hash_ty, &origin, spend).await
+            })
+        }
+    }
+
+    /// a device which signs its first request, then never answers again
+    struct StallingDevice(SigningKey, std::sync::atomic::AtomicBool);
+    impl ExternalSigner for StallingDevice {
+        type Error = PSBTSigningError;
+        fn owns_fingerprint(&self, f: Fingerprint) -> bool {
+            self.0.owns_fingerprint(f)
+        }
+        fn sign_taproot<'a>(
+            &'a self,
+            sighash: TapSighashHash,
+            hash_ty: bitcoin::SchnorrSighashType,
+            origin: &'a KeySource,
+            spend: SpendPath,
+        ) -> Pin<Box<dyn Future<Output = Result<SchnorrSig, PSBTSigningError>> + Send + 'a>>
+        {
+            Box::pin(async move {
+                if self.1.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                    std::future::pending::<()>().await;
+                }
+                self.0.sign_taproot(sighash, hash_ty, origin, spend).await
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelled_signing_leaves_psbt_unchanged() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] = taproot_input(
+            &secp,
+            &derive(&secp, &ours, "m/0"),
+            &[derive(&secp, &ours, "m/1")],
+        );
+        let original = psbt.clone();
+        let device = StallingDevice(test_key(1), Default::default());
+        let signing = sign_psbt_input_external(&device, &mut psbt, 0, hash_ty);
+        let timeout = std::time::Duration::from_millis(50);
+        assert!(tokio::time::timeout(timeout, signing).await.is_err());
+        // the first signature was made, but never written
+        assert!(device.1.load(std::sync::atomic::Ordering::SeqCst));
+        assert_eq!(psbt, original);
+
+        let summary = sign_psbt_input_external(&ours, &mut psbt, 0, hash_ty)
+            .await
+            .unwrap();
+        assert!(summary.key_path_signed);
+        assert_eq!(summary.script_sigs_added, 1);
+    }
+
+    #[tokio::test]
+    async fn external_signer_matches_local_signing() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] = taproot_input(
+            &secp,
+            &derive(&secp, &ours, "m/0"),
+            &[
+                derive(&secp, &ours, "m/1"),
+                derive(&secp, &test_key(2), "m/2"),
+            ],
+        );
+        let mut local = psbt.clone();
+        let expected = ours
+            .sign_psbt_input_with_summary_mut(&mut local, &secp, 0, hash_ty)
+            .unwrap();
+        let summary = sign_psbt_input_external(&ours, &mut psbt, 0, hash_ty)
+            .await
+            .unwrap();
+        assert_eq!(summary, expected);
+        assert_eq!(psbt, local);
+    }
+
+    #[tokio::test]
+    async fn external_signatures_are_checked() {
+        let secp = Secp256k1::new();
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &test_key(1), "m/0"), &[]);
+        let device = WrongKeyDevice(test_key(2));
+        assert!(matches!(
+            sign_psbt_input_external(&device, &mut psbt, 0, hash_ty).await,
+            Err(ExternalSignError::InvalidSignature { .. })
+        ));
+        assert!(psbt.inputs[0].tap_key_sig.is_none());
+    }
+
+    #[tokio::test]
+    async fn external_checks_before_asking() {
+        use bitcoin::hashes::Hash;
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        let mut psbt = unsigned_psbt(2);
+        let leaf_key = derive(&secp, &ours, "m/1");
+        for input in &mut psbt.inputs {
+            *input = taproot_input(
+                &secp,
+                &derive(&secp, &ours, "m/0"),
+                std::slice::from_ref(&leaf_key),
+            );
+        }
+        let device = StallingDevice(test_key(1), Default::default());
+
+        // a merkle root the output does not commit to is never signed for
+        let mut wrong_root = psbt.clone();
+        wrong_root.inputs[0].tap_merkle_root = Some(Hash::hash(b"not the tree"));
+        assert!(matches!(
+            sign_psbt_input_external(&device, &mut wrong_root, 0, hash_ty).await,
+            Err(ExternalSignError::Signing(
+                PSBTSigningError::OutputKeyMismatch { index: 0, .. }
+            ))
+        ));
+        let single = bitcoin::SchnorrSighashType::Single;
+        assert!(matches!(
+            sign_psbt_input_external(&device, &mut psbt, 1, single).await,
+            Err(ExternalSignError::Signing(
+                PSBTSigningError::SingleWithoutOutput { index: 1 }
+            ))
+        ));
+        assert!(!device.1.load(std::sync::atomic::Ordering::SeqCst));
+
+        // a leaf listed twice is signed once
+        let listed = &mut psbt.inputs[0]
+            .tap_key_origins
+            .get_mut(&leaf_key.0)
+            .unwrap()
+            .0;
+        listed.push(listed[0]);
+        let summary = sign_psbt_input_external(&ours, &mut psbt, 0, hash_ty)
+            .await
+            .unwrap();
+        assert!(summary.key_path_signed);
+        assert_eq!(summary.script_sigs_added, 1);
+
+        let foreign = (
+            test_key(2).0[0].fingerprint(&secp),
+            bitcoin::util::bip32::DerivationPath::master(),
+        );
+        assert!(matches!(
+            ours.sign_taproot(Hash::hash(b"sighash"), hash_ty, &foreign, SpendPath::ScriptPath(Hash::hash(b"leaf"))).await,
+            Err(PSBTSigningError::UnknownFingerprint(f)) if f == foreign.0
+        ));
+    }
+}
@@ -0,0 +1,984 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Turning a PSBT signed for simple spends into a broadcastable transaction.
+//! Anything needing more than one signature should go through miniscript, see
+//! [`crate::external_api::finalize_psbt_format_api`].
+use super::*;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::util::taproot::{ControlBlock, TapBranchHash};
+use bitcoin::Witness;
+
+/// Errors from [`SigningKey::finalize_and_extract`]
+#[derive(Debug, Clone)]
+pub enum FinalizeError {
+    /// the input has no signature and no final witness
+    NotSigned(usize),
+    /// the input has more signatures than a single-signature spend uses
+    UnsupportedSignatures(usize),
+    /// `tap_scripts` has no control block and leaf script for the leaf the
+    /// input is signed for
+    MissingTapScript { index: usize, leaf: TapLeafHash },
+    /// `tap_script_sigs` has no signature for the leaf
+    MissingLeafSignature { index: usize, leaf: TapLeafHash },
+    /// the leaf was finalized as needing no signature, but its script
+    /// checks one
+    LeafNeedsSignature { index: usize, leaf: TapLeafHash },
+    /// `tap_script_sigs` has fewer signatures for the multisig leaf than its
+    /// threshold
+    NotEnoughSignatures {
+        index: usize,
+        leaf: TapLeafHash,
+        remaining: usize,
+    },
+    /// the control block in `tap_scripts` for the leaf does not commit to
+    /// the input's `tap_merkle_root`
+    InvalidControlBlock {
+        index: usize,
+        leaf: TapLeafHash,
+        error: ControlBlockError,
+    },
+    /// the leaf's script is not a CHECKSIGADD multisig
+    NotMultisig { index: usize, leaf: TapLeafHash },
+    /// the annex recorded for the input is malformed or lacks the `0x50`
+    /// prefix, see [`crate::proprietary::annex`]
+    InvalidAnnex(usize),
+    /// there is no input at the index
+    NoInputAtIndex(usize),
+}
+
+impl Display for FinalizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for FinalizeError {}
+
+/// Errors from [`validate_control_block`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlBlockError {
+    /// a control block is 33 bytes plus 32 for each of at most 128 hashes
+    InvalidLength(usize),
+    /// the merkle path from the leaf does not hash to the expected root
+    MerkleRootMismatch {
+        expected: Option<TapBranchHash>,
+        computed: TapBranchHash,
+    },
+    /// the internal key tweaked by the root has the other parity
+    ParityMismatch,
+}
+
+impl Display for ControlBlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for ControlBlockError {}
+
+impl SigningKey {
+    /// Assemble the witness for every input and extract the transaction.
+    ///
+    /// Inputs may be taproot key path spends, taproot script path spends
+    /// with a single script signature, or p2wpkh spends, nested in p2sh or
+    /// not, with either a `witness_utxo` or a `non_witness_utxo`. An
+    /// unsigned taproot input with exactly one leaf which checks no
+    /// signature, such as a CTV leaf, spends that leaf as
+    /// [`signature_free_witness`] does. Taproot witnesses end with the annex
+    /// recorded by [`crate::proprietary::set_annex`], if any. Inputs which
+    /// already have a final witness are left as is.
+    pub fn finalize_and_extract(
+        &self,
+        mut psbt: PartiallySignedTransaction,
+    ) -> Result<bitcoin::Transaction, FinalizeError> {
+        let txins = &psbt.unsigned_tx.input;
+        for (idx, (input, txin)) in psbt.inputs.iter_mut().zip(txins).enumerate() {
+            if input.final_script_witness.is_some() {
+                continue;
+            }
+            let (witness, script_sig) = single_sig_witness(idx, input, &txin.previous_output)?;
+            input.final_script_witness = Some(witness);
+            input.final_script_sig = script_sig;
+            clear_signing_fields(input);
+        }
+        Ok(psbt.extract_tx())
+    }
+}
+
+/// The witness spending input `idx` through `leaf`, a single signature
+/// script such as `<pk> OP_CHECKSIG`: the leaf's one signature, the script,
+/// and its control block from `tap_scripts`.
+pub fn script_path_witness(
+    psbt: &PartiallySignedTransaction,
+    idx: usize,
+    leaf: TapLeafHash,
+) -> Result<Witness, FinalizeError> {
+    let input = psbt
+        .inputs
+        .get(idx)
+        .ok_or(FinalizeError::NoInputAtIndex(idx))?;
+    leaf_witness(idx, input, leaf)
+}
+
+fn leaf_witness(
+    idx: usize,
+    input: &bitcoin::psbt::Input,
+    leaf: TapLeafHash,
+) -> Result<Witness, FinalizeError> {
+    let mut sigs = input
+        .tap_script_sigs
+        .iter()
+        .filter(|((_, l), _)| *l == leaf)
+        .map(|(_, sig)| sig);
+    let sig = sigs
+        .next()
+        .ok_or(FinalizeError::MissingLeafSignature { index: idx, leaf })?;
+    if sigs.next().is_some() {
+        return Err(FinalizeError::UnsupportedSignatures(idx));
+    }
+    let (cb, script) = tap_script(idx, input, leaf)?;
+    taproot_witness(
+        idx,
+        input,
+        vec![sig.to_vec(), script.to_bytes(), cb.serialize()],
+    )
+}
+
+/// `stack` as a taproot witness for input `idx`, with the annex recorded by
+/// [`crate::proprietary::set_annex`], if any, as its last element
+pub(crate) fn taproot_witness(
+    idx: usize,
+    input: &bitcoin::psbt::Input,
+    mut stack: Vec<Vec<u8>>,
+) -> Result<Witness, FinalizeError> {
+    let annex = crate::proprietary::annex(input).map_err(|_| FinalizeError::InvalidAnnex(idx))?;
+    if let Some(annex) = annex {
+        bitcoin::util::sighash::Annex::new(&annex).map_err(|_| FinalizeError::InvalidAnnex(idx))?;
+        stack.push(annex);
+    }
+    Ok(Witness::from_vec(stack))
+}
+
+/// The witness spending input `idx` through `leaf`, a script which checks
+/// no signature, such as Sapio's `<hash> OP_CHECKTEMPLATEVERIFY` leaves: just
+/// the script and its control block from `tap_scripts`. Errors if the script
+/// has a signature opcode, as the witness would then fail.
+pub fn signature_free_witness(
+    psbt: &PartiallySignedTransaction,
+    idx: usize,
+    leaf: TapLeafHash,
+) -> Result<Witness, FinalizeError> {
+    let input = psbt
+        .inputs
+        .get(idx)
+        .ok_or(FinalizeError::NoInputAtIndex(idx))?;
+    free_leaf_witness(idx, input, leaf)
+}
+
+fn free_leaf_witness(
+    idx: usize,
+    input: &bitcoin::psbt::Input,
+    leaf: TapLeafHash,
+) -> Result<Witness, FinalizeError> {
+    let (cb, script) = tap_script(idx, input, leaf)?;
+    if checks_signature(script) {
+        return Err(FinalizeError::LeafNeedsSignature { index: idx, leaf });
+    }
+    taproot_witness(idx, input, vec![script.to_bytes(), cb.serialize()])
+}
+
+/// Whether `script` has a signature checking opcode, or cannot be parsed so
+/// may have one
+fn checks_signature(script: &Script) -> bool {
+    use bitcoin::blockdata::opcodes::all::*;
+    use bitcoin::blockdata::script::Instruction;
+    script.instructions().any(|ins| match ins {
+        Ok(Instruction::Op(op)) => [
+            OP_CHECKSIG,
+            OP_CHECKSIGVERIFY,
+            OP_CHECKSIGADD,
+            OP_CHECKMULTISIG,
+            OP_CHECKMULTISIGVERIFY,
+        ]
+        .contains(&op),
+        Ok(Instruction::PushBytes(_)) => false,
+        Err(_) => true,
+    })
+}
+
+/// The control block and script for `leaf` from the input's `tap_scripts`,
+/// checked against `tap_merkle_root` if the input has one
+pub(crate) fn tap_script(
+    idx: usize,
+    input: &bitcoin::psbt::Input,
+    leaf: TapLeafHash,
+) -> Result<(&ControlBlock, &Script), FinalizeError> {
+    let (cb, script) = input
+        .tap_scripts
+        .iter()
+        .find(|(_, (script, ver))| TapLeafHash::from_script(script, *ver) == leaf)
+        .map(|(cb, (script, _))| (cb, script))
+        .ok_or(FinalizeError::MissingTapScript { index: idx, leaf })?;
+    if input.tap_merkle_root.is_some() {
+        validate_control_block(cb, script, input.tap_merkle_root).map_err(|error| {
+            FinalizeError::InvalidControlBlock {
+                index: idx,
+                leaf,
+                error,
+            }
+        })?;
+    }
+    Ok((cb, script))
+}
+
+/// The merkle root `cb` proves `leaf_script` is under
+pub(crate) fn control_block_root(cb: &ControlBlock, leaf_script: &Script) -> TapBranchHash {
+    let leaf = TapLeafHash::from_script(leaf_script, cb.leaf_version);
+    cb.merkle_branch.as_inner().iter().fold(
+        TapBranchHash::from_inner(leaf.into_inner()),
+        |node, sibling| {
+            TapBranchHash::from_node_hashes(sha256::Hash::from_inner(node.into_inner()), *sibling)
+        },
+    )
+}
+
+/// Checks that `cb` proves `leaf_script` is in the tree with `merkle_root`,
+/// and that its parity bit is that of the internal key tweaked by the root,
+/// so a witness built from it can be spent. It does not check the internal
+/// key is the one expected, only that the control block agrees with itself.
+pub fn validate_control_block(
+    cb: &ControlBlock,
+    leaf_script: &Script,
+    merkle_root: Option<TapBranchHash>,
+) -> Result<(), ControlBlockError> {
+    let len = cb.size();
+    if len < 33 || (len - 33) % 32 != 0 || (len - 33) / 32 > 128 {
+        return Err(ControlBlockError::InvalidLength(len));
+    }
+    let computed = control_block_root(cb, leaf_script);
+    if merkle_root != Some(computed) {
+        return Err(ControlBlockError::MerkleRootMismatch {
+            expected: merkle_root,
+            computed,
+        });
+    }
+    let (_, parity) = cb.internal_key.tap_tweak(crate::global_secp(), merkle_root);
+    if parity != cb.output_key_parity {
+        return Err(ControlBlockError::ParityMismatch);
+    }
+    Ok(())
+}
+
+/// The witness for input `idx`, which spends `outpoint`, and for a nested
+/// p2wpkh spend the `scriptSig` pushing its redeem script
+fn single_sig_witness(
+    idx: usize,
+    input: &bitcoin::psbt::Input,
+    outpoint: &bitcoin::OutPoint,
+) -> Result<(Witness, Option<Script>), FinalizeError> {
+    if let Some(sig) = input.tap_key_sig {
+        return Ok((taproot_witness(idx, input, vec![sig.to_vec()])?, None));
+    }
+    if let Some((_, leaf)) = input.tap_script_sigs.keys().next() {
+        if input.tap_script_sigs.len() > 1 {
+            return Err(FinalizeError::UnsupportedSignatures(idx));
+        }
+        return Ok((leaf_witness(idx, input, *leaf)?, None));
+    }
+    let spk = crate::spent_utxo(idx, input, outpoint)
+        .ok()
+        .map(|utxo| utxo.script_pubkey);
+    let is_wpkh = spk
+        .as_ref()
+        .and_then(|spk| ecdsa::witness_program(input, spk))
+        .map_or(false, |program| program.is_v0_p2wpkh());
+    match input.partial_sigs.iter().next() {
+        Some((pk, sig)) if is_wpkh && input.partial_sigs.len() == 1 => {
+            let script_sig = spk
+                .filter(|spk| spk.is_p2sh())
+                .and(input.redeem_script.as_ref())
+                .map(|rs| {
+                    bitcoin::blockdata::script::Builder::new()
+                        .push_slice(rs.as_bytes())
+                        .into_script()
+                });
+            Ok((
+                Witness::from_vec(vec![sig.to_vec(), pk.to_bytes()]),
+                script_sig,
+            ))
+        }
+        Some(_) => Err(FinalizeError::UnsupportedSignatures(idx)),
+        None => signature_free_leaf(idx, input)
+            .map(|witness| (witness, None))
+            .ok_or(FinalizeError::NotSigned(idx)),
+    }
+}
+
+/// The witness for the input's only leaf which checks no signature, if it
+/// has exactly one
+fn signature_free_leaf(idx: usize, input: &bitcoin::psbt::Input) -> Option<Witness> {
+    let mut free = input
+        .tap_scripts
+        .values()
+        .filter(|(script, _)| !checks_signature(script));
+    let (script, ver) = free.next()?;
+    if free.next().is_some() {
+        return None;
+    }
+    free_leaf_witness(idx, input, TapLeafHash::from_script(script, *ver)).ok()
+}
+
+/// Strip the key derivation hints (`xpub`, and every input's and output's
+/// `bip32_derivation` and `tap_key_origins`) which a signed PSBT no longer
+/// needs, leaking less about our wallet to whoever receives it. Signatures,
+/// scripts, and everything else a finalizer uses are kept.
+pub fn minimize_for_broadcast(mut psbt: PartiallySignedTransaction) -> PartiallySignedTransaction {
+    psbt.xpub.clear();
+    for input in &mut psbt.inputs {
+        input.bip32_derivation.clear();
+        input.tap_key_origins.clear();
+    }
+    for output in &mut psbt.outputs {
+        output.bip32_derivation.clear();
+        output.tap_key_origins.clear();
+    }
+    psbt
+}
+
+/// How many more script signatures for `leaf` input `input_idx` needs before
+/// the leaf's k-of-n tapscript multisig can be satisfied, for coordinating
+/// the cosigners. The leaf's script must be in `tap_scripts` and have the
+/// shape of a [`crate::CheckSigAddLeaf`], and its keys and threshold are read
+/// from it, so signatures by keys it does not list are not counted.
+pub fn signatures_remaining(
+    psbt: &PartiallySignedTransaction,
+    input_idx: usize,
+    leaf: TapLeafHash,
+) -> Result<usize, FinalizeError> {
+    let input = psbt
+        .inputs
+        .get(input_idx)
+        .ok_or(FinalizeError::NoInputAtIndex(input_idx))?;
+    let (_, script) = tap_script(input_idx, input, leaf)?;
+    let (keys, threshold) =
+        crate::checksigadd::parse(script).ok_or(FinalizeError::NotMultisig {
+            index: input_idx,
+            leaf,
+        })?;
+    let present = keys
+        .iter()
+        .filter(|pk| input.tap_script_sigs.contains_key(&(**pk, leaf)))
+        .count();
+    Ok(threshold.saturating_sub(present))
+}
+
+/// Whether input `idx` has every signature `path` needs, so finalizing it
+/// for that path can succeed. The key path needs `tap_key_sig`. A leaf
+/// needs its script in `tap_scripts`, and must be a `<pk> OP_CHECKSIG` with
+/// a signature for `pk`, or a [`crate::CheckSigAddLeaf`] with at least its
+/// threshold of signatures. A leaf which checks no signature is always
+/// ready, and other scripts are never reported ready. An input which is
+/// already finalized is ready, and a missing one is not.
+pub fn is_ready_to_finalize(
+    psbt: &PartiallySignedTransaction,
+    idx: usize,
+    path: &crate::external_api::SpendPath,
+) -> bool {
+    use crate::external_api::SpendPath;
+    let input = match psbt.inputs.get(idx) {
+        Some(input) => input,
+        None => return false,
+    };
+    if input.final_script_witness.is_some() {
+        return true;
+    }
+    let leaf = match path {
+        SpendPath::KeyPath { .. } => return input.tap_key_sig.is_some(),
+        SpendPath::ScriptPath(leaf) => *leaf,
+    };
+    let script = match tap_script(idx, input, leaf) {
+        Ok((_, script)) => script,
+        Err(_) => return false,
+    };
+    if !checks_signature(script) {
+        return true;
+    }
+    let signed = |pk: &XOnlyPublicKey| input.tap_script_sigs.contains_key(&(*pk, leaf));
+    if let Some((keys, threshold)) = crate::checksigadd::parse(script) {
+        return keys.iter().filter(|pk| signed(pk)).count() >= threshold;
+    }
+    let bytes = script.as_bytes();
+    match (bytes.len(), bytes.first(), bytes.last()) {
+        (34, Some(0x20), Some(0xac)) => XOnlyPublicKey::from_slice(&bytes[1..33])
+            .map(|pk| signed(&pk))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// BIP-174 finalizers drop everything but the UTXOs, final fields, and
+/// unknowns
+pub(crate) fn clear_signing_fields(input: &mut bitcoin::psbt::Input) {
+    *input = bitcoin::psbt::Input {
+        non_witness_utxo: input.non_witness_utxo.take(),
+        witness_utxo: input.witness_utxo.take(),
+        final_script_sig: input.final_script_sig.take(),
+        final_script_witness: input.final_script_witness.take(),
+        proprietary: std::mem::take(&mut input.proprietary),
+        unknown: std::mem::take(&mut input.unknown),
+        ..Default::default()
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    #[test]
+    fn finalize_key_path() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &ours, "m/0"), &[]);
+        ours.sign_psbt_mut(&mut psbt, &secp, bitcoin::SchnorrSighashType::Default)
+            .unwrap();
+        let tx = ours.finalize_and_extract(psbt).unwrap();
+        let witness = tx.input[0].witness.to_vec();
+        assert_eq!(witness.len(), 1);
+        assert_eq!(witness[0].len(), 64);
+    }
+
+    #[test]
+    fn finalize_script_path() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let (pk, origin) = derive(&secp, &ours, "m/1");
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &test_key(2), "m/0"), &[(pk, origin)]);
+        ours.sign_psbt_mut(&mut psbt, &secp, bitcoin::SchnorrSighashType::All)
+            .unwrap();
+
+        let mut missing = psbt.clone();
+        missing.inputs[0].tap_scripts.clear();
+        assert!(matches!(
+            ours.finalize_and_extract(missing),
+            Err(FinalizeError::MissingTapScript { index: 0, .. })
+        ));
+
+        let tx = ours.finalize_and_extract(psbt).unwrap();
+        let witness = tx.input[0].witness.to_vec();
+        assert_eq!(witness.len(), 3);
+        assert_eq!(witness[0].len(), 65);
+        assert_eq!(witness[1], checksig_script(&pk).to_bytes());
+    }
+
+    #[test]
+    fn control_block_checked() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let (pk, origin) = derive(&secp, &ours, "m/1");
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] = taproot_input(
+            &secp,
+            &derive(&secp, &test_key(2), "m/0"),
+            &[(pk, origin), derive(&secp, &test_key(3), "m/1")],
+        );
+        let leaf = TapLeafHash::from_script(
+            &checksig_script(&pk),
+            bitcoin::util::taproot::LeafVersion::TapScript,
+        );
+        ours.sign_psbt_mut(&mut psbt, &secp, bitcoin::SchnorrSighashType::All)
+            .unwrap();
+        let root = psbt.inputs[0].tap_merkle_root;
+        let (cb, script) = tap_script(0, &psbt.inputs[0], leaf).unwrap();
+        let (cb, script) = (cb.clone(), script.clone());
+        assert_eq!(validate_control_block(&cb, &script, root), Ok(()));
+
+        let mut tampered = cb.clone();
+        let mut branch = tampered.merkle_branch.into_inner();
+        branch[0] = sha256::Hash::hash(b"not the sibling");
+        tampered.merkle_branch =
+            bitcoin::util::taproot::TaprootMerkleBranch::from_inner(branch).unwrap();
+        assert!(matches!(
+            validate_control_block(&tampered, &script, root),
+            Err(ControlBlockError::MerkleRootMismatch { expected, .. }) if expected == root
+        ));
+        let mut flipped = cb.clone();
+        flipped.output_key_parity = match cb.output_key_parity {
+            bitcoin::secp256k1::Parity::Even => bitcoin::secp256k1::Parity::Odd,
+            bitcoin::secp256k1::Parity::Odd => bitcoin::secp256k1::Parity::Even,
+        };
+        assert_eq!(
+            validate_control_block(&flipped, &script, root),
+            Err(ControlBlockError::ParityMismatch)
+        );
+
+        // a tampered control block is refused rather than finalized
+        psbt.inputs[0].tap_scripts.remove(&cb);
+        psbt.inputs[0].tap_scripts.insert(
+            tampered,
+            (script, bitcoin::util::taproot::LeafVersion::TapScript),
+        );
+        assert!(matches!(
+            script_path_witness(&psbt, 0, leaf),
+            Err(FinalizeError::InvalidControlBlock {
+                index: 0,
+                error: ControlBlockError::MerkleRootMismatch { .. },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn finalize_with_annex() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        let (pk, origin) = derive(&secp, &ours, "m/1");
+        let mut psbt = unsigned_psbt(2);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &ours, "m/0"), &[]);
+        psbt.inputs[1] = taproot_input(&secp, &derive(&secp, &test_key(2), "m/0"), &[(pk, origin)]);
+        let annex = vec![0x50, 1, 2, 3];
+        let opts = SigningOptions {
+            annex: Some(annex.clone()),
+            ..Default::default()
+        };
+        ours.sign_psbt_all_with_options_mut(&mut psbt, &secp, hash_ty, &opts)
+            .unwrap();
+        let mut bad = psbt.clone();
+        crate::proprietary::set_annex(&mut bad.inputs[1], vec![0x51]);
+        assert!(matches!(
+            ours.finalize_and_extract(bad),
+            Err(FinalizeError::InvalidAnnex(1))
+        ));
+
+        let utxos: Vec<_> = psbt
+            .inputs
+            .iter()
+            .map(|i| i.witness_utxo.clone().unwrap())
+            .collect();
+        let tx = ours.finalize_and_extract(psbt).unwrap();
+        let mut cache = bitcoin::util::sighash::SighashCache::new(&tx);
+        let prevouts = Prevouts::All(&utxos);
+        for (idx, txin) in tx.input.iter().enumerate() {
+            let witness = txin.witness.to_vec();
+            assert_eq!(witness.last(), Some(&annex));
+            let (key, path) = if idx == 0 {
+                let output_key = &utxos[0].script_pubkey[2..];
+                (XOnlyPublicKey::from_slice(output_key).unwrap(), None)
+            } else {
+                let script = Script::from(witness[1].clone());
+                let ver = bitcoin::util::taproot::LeafVersion::TapScript;
+                (
+                    pk,
+                    Some((TapLeafHash::from_script(&script, ver), DEFAULT_CODESEP)),
+                )
+            };
+            let sighash = cache
+                .taproot_signature_hash(
+                    idx,
+                    &prevouts,
+                    Some(Annex::new(&annex).unwrap()),
+                    path,
+                    hash_ty,
+                )
+                .unwrap();
+            let sig = SchnorrSig::from_slice(&witness[0]).unwrap();
+            secp.verify_schnorr(&sig.sig, &sighash_message(&sighash), &key)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn chosen_leaf_witness() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let (pk, origin) = derive(&secp, &ours, "m/1");
+        let (other, other_origin) = derive(&secp, &test_key(3), "m/1");
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] = taproot_input(
+            &secp,
+            &derive(&secp, &test_key(2), "m/0"),
+            &[(pk, origin), (other, other_origin)],
+        );
+        let ver = bitcoin::util::taproot::LeafVersion::TapScript;
+        let ours_leaf = TapLeafHash::from_script(&checksig_script(&pk), ver);
+        let other_leaf = TapLeafHash::from_script(&checksig_script(&other), ver);
+        ours.sign_psbt_mut(&mut psbt, &secp, bitcoin::SchnorrSighashType::All)
+            .unwrap();
+        assert!(matches!(
+            script_path_witness(&psbt, 0, other_leaf),
+            Err(FinalizeError::MissingLeafSignature { index: 0, leaf }) if leaf == other_leaf
+        ));
+        assert!(matches!(
+            script_path_witness(&psbt, 1, ours_leaf),
+            Err(FinalizeError::NoInputAtIndex(1))
+        ));
+
+        let witness = script_path_witness(&psbt, 0, ours_leaf).unwrap().to_vec();
+        assert_eq!(witness[1], checksig_script(&pk).to_bytes());
+        // the control block commits to the output being spent
+        let cb = bitcoin::util::taproot::ControlBlock::from_slice(&witness[2]).unwrap();
+        let spk = &psbt.inputs[0].witness_utxo.as_ref().unwrap().script_pubkey;
+        let output_key = XOnlyPublicKey::from_slice(&spk[2..]).unwrap();
+        assert!(cb.verify_taproot_commitment(&secp, output_key, &checksig_script(&pk)));
+        // and the signature is valid for the leaf
+        let utxos = [psbt.inputs[0].witness_utxo.clone().unwrap()];
+        let tx = psbt.clone().extract_tx();
+        let sighash = bitcoin::util::sighash::SighashCache::new(&tx)
+            .taproot_script_spend_signature_hash(
+                0,
+                &Prevouts::All(&utxos),
+                bitcoin::util::sighash::ScriptPath::with_defaults(&checksig_script(&pk)),
+                bitcoin::SchnorrSighashType::All,
+            )
+            .unwrap();
+        let msg = bitcoin::secp256k1::Message::from_digest_slice(&sighash[..]).unwrap();
+        let sig = SchnorrSig::from_slice(&witness[0]).unwrap();
+        secp.verify_schnorr(&sig.sig, &msg, &pk).unwrap();
+    }
+
+    /// Runs input `idx` of `tx` through miniscript's interpreter, checking
+    /// its signatures
+    fn assert_spends(tx: &bitcoin::Transaction, idx: usize, prevouts: &[TxOut]) {
+        let txin = &tx.input[idx];
+        let interpreter = miniscript::Interpreter::from_txdata(
+            &prevouts[idx].script_pubkey,
+            &txin.script_sig,
+            &txin.witness,
+            0,
+            0,
+            sha256::Hash::from_inner([0; 32]),
+        )
+        .unwrap();
+        let prevouts = Prevouts::All(prevouts);
+        let satisfied = interpreter
+            .iter(&Secp256k1::new(), tx, idx, &prevouts)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(!satisfied.is_empty());
+    }
+
+    #[test]
+    fn finalize_v0_without_witness_utxo_and_nested() {
+        use bitcoin::{OutPoint, Transaction};
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let mut psbt = unsigned_psbt(2);
+        // input 0 only has the transaction it spends
+        let (mut bare, _) = wpkh_input(&secp, &ours, "m/84/0");
+        let prev = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![bare.witness_utxo.take().unwrap()],
+        };
+        psbt.unsigned_tx.input[0].previous_output = OutPoint::new(prev.txid(), 0);
+        let mut prevouts = prev.output.clone();
+        bare.non_witness_utxo = Some(prev);
+        psbt.inputs[0] = bare;
+        // input 1 is p2sh-p2wpkh
+        let (mut nested, pk) = wpkh_input(&secp, &ours, "m/49/0");
+        let redeem = Script::new_v0_p2wpkh(&pk.wpubkey_hash().unwrap());
+        nested.witness_utxo.as_mut().unwrap().script_pubkey =
+            Script::new_p2sh(&redeem.script_hash());
+        nested.redeem_script = Some(redeem.clone());
+        prevouts.push(nested.witness_utxo.clone().unwrap());
+        psbt.inputs[1] = nested;
+
+        ours.sign_psbt_mut(&mut psbt, &secp, bitcoin::SchnorrSighashType::All)
+            .unwrap();
+        let tx = ours.finalize_and_extract(psbt).unwrap();
+        assert!(tx.input[0].script_sig.is_empty());
+        assert_eq!(
+            tx.input[1].script_sig,
+            bitcoin::blockdata::script::Builder::new()
+                .push_slice(redeem.as_bytes())
+                .into_script()
+        );
+        for idx in 0..2 {
+            assert_spends(&tx, idx, &prevouts);
+        }
+    }
+
+    #[test]
+    fn minimized_still_finalizes() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let (pk, origin) = derive(&secp, &ours, "m/1");
+        let mut psbt = unsigned_psbt(3);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &ours, "m/0"), &[]);
+        psbt.inputs[1] = taproot_input(&secp, &derive(&secp, &test_key(2), "m/0"), &[(pk, origin)]);
+        psbt.inputs[2] = wpkh_input(&secp, &ours, "m/84/0").0;
+        psbt.xpub.insert(
+            ours.pubkey(&secp)[0],
+            (ours.0[0].fingerprint(&secp), DerivationPath::master()),
+        );
+        ours.sign_psbt_mut(&mut psbt, &secp, bitcoin::SchnorrSighashType::All)
+            .unwrap();
+
+        let minimal = minimize_for_broadcast(psbt.clone());
+        assert!(minimal.xpub.is_empty());
+        for (input, signed) in minimal.inputs.iter().zip(&psbt.inputs) {
+            assert!(input.tap_key_origins.is_empty());
+            assert!(input.bip32_derivation.is_empty());
+            assert_eq!(input.tap_key_sig, signed.tap_key_sig);
+            assert_eq!(input.tap_script_sigs, signed.tap_script_sigs);
+            assert_eq!(input.partial_sigs, signed.partial_sigs);
+        }
+        assert!(serialize(&minimal).len() < serialize(&psbt).len());
+        assert_eq!(
+            ours.finalize_and_extract(minimal).unwrap(),
+            ours.finalize_and_extract(psbt).unwrap()
+        );
+    }
+
+    #[test]
+    fn two_of_three_remaining() {
+        use crate::fixtures::Leaf;
+        use bitcoin::util::taproot::LeafVersion;
+        let secp = Secp256k1::new();
+        let signers: Vec<_> = (1..=3)
+            .map(|i| derive(&secp, &test_key(i), "m/0"))
+            .collect();
+        let keys: Vec<_> = signers.iter().map(|(pk, _)| *pk).collect();
+        let script = checksigadd_script(&keys, 2);
+        let leaf = TapLeafHash::from_script(&script, LeafVersion::TapScript);
+        let internal = derive(&secp, &test_key(4), "m/0");
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] = crate::fixtures::taproot_input(
+            &secp,
+            &internal,
+            &[Leaf {
+                weight: 1,
+                script,
+                version: LeafVersion::TapScript,
+                signers,
+            }],
+            10_000,
+        );
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        assert_eq!(signatures_remaining(&psbt, 0, leaf).unwrap(), 2);
+        test_key(1)
+            .sign_psbt_mut(&mut psbt, &secp, hash_ty)
+            .unwrap();
+        assert_eq!(signatures_remaining(&psbt, 0, leaf).unwrap(), 1);
+        // a signature by a key the script does not list does not count
+        let sig = *psbt.inputs[0].tap_script_sigs.values().next().unwrap();
+        let stranger = derive(&secp, &test_key(5), "m/0").0;
+        psbt.inputs[0].tap_script_sigs.insert((stranger, leaf), sig);
+        assert_eq!(signatures_remaining(&psbt, 0, leaf).unwrap(), 1);
+        test_key(3)
+            .sign_psbt_mut(&mut psbt, &secp, hash_ty)
+            .unwrap();
+        assert_eq!(signatures_remaining(&psbt, 0, leaf).unwrap(), 0);
+
+        let other = TapLeafHash::from_script(&Script::new(), LeafVersion::TapScript);
+        assert!(matches!(
+            signatures_remaining(&psbt, 0, other),
+            Err(FinalizeError::MissingTapScript { index: 0, .. })
+        ));
+        assert!(matches!(
+            signatures_remaining(&psbt, 1, leaf),
+            Err(FinalizeError::NoInputAtIndex(1))
+        ));
+        let single = derive(&secp, &test_key(1), "m/1");
+        psbt.inputs[0] = taproot_input(&secp, &internal, std::slice::from_ref(&single));
+        let single_leaf =
+            TapLeafHash::from_script(&checksig_script(&single.0), LeafVersion::TapScript);
+        assert!(matches!(
+            signatures_remaining(&psbt, 0, single_leaf),
+            Err(FinalizeError::NotMultisig { index: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn sighash_default_is_64_bytes() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let (pk, origin) = derive(&secp, &ours, "m/1");
+        for (hash_ty, len) in [
+            (bitcoin::SchnorrSighashType::Default, 64),
+            (bitcoin::SchnorrSighashType::All, 65),
+        ] {
+            let mut psbt = unsigned_psbt(2);
+            psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &ours, "m/0"), &[]);
+            psbt.inputs[1] = taproot_input(
+                &secp,
+                &derive(&secp, &test_key(2), "m/0"),
+                &[(pk, origin.clone())],
+            );
+            ours.sign_psbt_mut(&mut psbt, &secp, hash_ty).unwrap();
+            let key_sig = psbt.inputs[0].tap_key_sig.unwrap();
+            let leaf_sig = *psbt.inputs[1].tap_script_sigs.values().next().unwrap();
+            for sig in [key_sig, leaf_sig] {
+                assert_eq!(sig.hash_ty, hash_ty);
+                assert_eq!(sig.to_vec().len(), len);
+            }
+            // the sighash type survives the PSBT encoding
+            let decoded: PartiallySignedTransaction = deserialize(&serialize(&psbt)).unwrap();
+            assert_eq!(decoded.inputs[0].tap_key_sig, Some(key_sig));
+            assert_eq!(
+                decoded.inputs[1].tap_script_sigs,
+                psbt.inputs[1].tap_script_sigs
+            );
+
+            let tx = ours.finalize_and_extract(decoded).unwrap();
+            assert_eq!(tx.input[0].witness.to_vec()[0].len(), len);
+            assert_eq!(tx.input[1].witness.to_vec()[0].len(), len);
+        }
+    }
+
+    #[test]
+    fn ready_for_path() {
+        use crate::external_api::SpendPath;
+        use crate::fixtures::Leaf;
+        use bitcoin::util::taproot::LeafVersion;
+        let secp = Secp256k1::new();
+        let signers: Vec<_> = (1..=3)
+            .map(|i| derive(&secp, &test_key(i), "m/0"))
+            .collect();
+        let keys: Vec<_> = signers.iter().map(|(pk, _)| *pk).collect();
+        let multisig_script = checksigadd_script(&keys, 2);
+        let single = checksig_script(&signers[0].0);
+        let internal = derive(&secp, &test_key(1), "m/9");
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] = crate::fixtures::taproot_input(
+            &secp,
+            &internal,
+            &[
+                Leaf {
+                    weight: 1,
+                    script: multisig_script.clone(),
+                    version: LeafVersion::TapScript,
+                    signers: signers.clone(),
+                },
+                Leaf {
+                    weight: 1,
+                    script: single.clone(),
+                    version: LeafVersion::TapScript,
+                    signers: vec![signers[0].clone()],
+                },
+            ],
+            10_000,
+        );
+        let key_path = SpendPath::KeyPath {
+            merkle_root: psbt.inputs[0].tap_merkle_root,
+        };
+        let multisig = SpendPath::ScriptPath(TapLeafHash::from_script(
+            &multisig_script,
+            LeafVersion::TapScript,
+        ));
+        let single =
+            SpendPath::ScriptPath(TapLeafHash::from_script(&single, LeafVersion::TapScript));
+        let ready = |psbt: &PartiallySignedTransaction| {
+            [&key_path, &multisig, &single].map(|path| is_ready_to_finalize(psbt, 0, path))
+        };
+        let hash_ty = bitcoin::SchnorrSighashType::Default;
+        assert_eq!(ready(&psbt), [false, false, false]);
+        assert!(!is_ready_to_finalize(&psbt, 1, &key_path));
+
+        // key 3 alone signs one of the multisig's two
+        test_key(3)
+            .sign_psbt_mut(&mut psbt, &secp, hash_ty)
+            .unwrap();
+        assert_eq!(ready(&psbt), [false, false, false]);
+        // key 1 holds the internal key, the single sig, and the second
+        test_key(1)
+            .sign_psbt_mut(&mut psbt, &secp, hash_ty)
+            .unwrap();
+        assert_eq!(ready(&psbt), [true, true, true]);
+
+        let mut unknown = psbt.clone();
+        unknown.inputs[0].tap_scripts.clear();
+        assert_eq!(ready(&unknown), [true, false, false]);
+
+        let participants = signers.iter().map(|(pk, _)| *pk).collect();
+        let leaf = crate::CheckSigAddLeaf::new(multisig_script, participants).unwrap();
+        leaf.finalize_input(&mut psbt, 0).unwrap();
+        assert_eq!(ready(&psbt), [true, true, true]);
+    }
+
+    #[test]
+    fn finalize_unsigned() {
+        let secp = Secp256k1::new();
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &test_key(2), "m/0"), &[]);
+        assert!(matches!(
+            test_key(1).finalize_and_extract(psbt),
+            Err(FinalizeError::NotSigned(0))
+        ));
+    }
+
+    #[test]
+    fn finalize_ctv_leaf() {
+        use crate::external_api::SpendPath;
+        use crate::fixtures::Leaf;
+        use bitcoin::blockdata::opcodes::all::OP_NOP4 as OP_CTV;
+        use bitcoin::util::taproot::LeafVersion;
+        let secp = Secp256k1::new();
+        let ctv = |hash: [u8; 32]| {
+            bitcoin::blockdata::script::Builder::new()
+                .push_slice(&hash)
+                .push_opcode(OP_CTV)
+                .into_script()
+        };
+        let leaf = |script: Script| Leaf {
+            weight: 1,
+            script,
+            version: LeafVersion::TapScript,
+            signers: vec![],
+        };
+        let other = derive(&secp, &test_key(3), "m/1").0;
+        let internal = derive(&secp, &test_key(2), "m/0");
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] = crate::fixtures::taproot_input(
+            &secp,
+            &internal,
+            &[leaf(ctv([7; 32])), leaf(checksig_script(&other))],
+            10_000,
+        );
+        let ver = LeafVersion::TapScript;
+        let ctv_leaf = TapLeafHash::from_script(&ctv([7; 32]), ver);
+        let sig_leaf = TapLeafHash::from_script(&checksig_script(&other), ver);
+
+        let witness = signature_free_witness(&psbt, 0, ctv_leaf).unwrap().to_vec();
+        assert_eq!(witness.len(), 2);
+        assert_eq!(witness[0], ctv([7; 32]).to_bytes());
+        let cb = ControlBlock::from_slice(&witness[1]).unwrap();
+        validate_control_block(&cb, &ctv([7; 32]), psbt.inputs[0].tap_merkle_root).unwrap();
+        assert!(matches!(
+            signature_free_witness(&psbt, 0, sig_leaf),
+            Err(FinalizeError::LeafNeedsSignature { index: 0, .. })
+        ));
+        assert!(is_ready_to_finalize(
+            &psbt,
+            0,
+            &SpendPath::ScriptPath(ctv_leaf)
+        ));
+        assert!(!is_ready_to_finalize(
+            &psbt,
+            0,
+            &SpendPath::ScriptPath(sig_leaf)
+        ));
+
+        // with no signatures, the only signature free leaf is spent
+        let tx = test_key(1).finalize_and_extract(psbt.clone()).unwrap();
+        assert_eq!(tx.input[0].witness.to_vec(), witness);
+
+        // but none is picked from several
+        psbt.inputs[0] = crate::fixtures::taproot_input(
+            &secp,
+            &internal,
+            &[leaf(ctv([7; 32])), leaf(ctv([8; 32]))],
+            10_000,
+        );
+        assert!(matches!(
+            test_key(1).finalize_and_extract(psbt),
+            Err(FinalizeError::NotSigned(0))
+        ));
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Building ready to sign taproot PSBTs from scratch for tests.
+use super::*;
+use bitcoin::blockdata::transaction::{OutPoint, TxIn};
+use bitcoin::util::taproot::{LeafVersion, NodeInfo, TaprootSpendInfo};
+use bitcoin::{Transaction, Txid, Witness};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// One leaf of a script tree, and the keys which sign for it
+pub(crate) struct Leaf {
+    /// the huffman weight of the leaf, higher is closer to the root
+    pub weight: u32,
+    pub script: Script,
+    pub version: LeafVersion,
+    pub signers: Vec<(XOnlyPublicKey, KeySource)>,
+}
+
+/// Builds a PSBT with one taproot input per call to
+/// [`PsbtBuilder::taproot_input`], spending prevouts `0..n` of the all zero
+/// txid.
+pub(crate) struct PsbtBuilder<'a, C: Verification> {
+    secp: &'a Secp256k1<C>,
+    inputs: Vec<bitcoin::psbt::Input>,
+    outputs: Vec<TxOut>,
+}
+
+impl<'a, C: Verification> PsbtBuilder<'a, C> {
+    pub(crate) fn new(secp: &'a Secp256k1<C>) -> Self {
+        PsbtBuilder {
+            secp,
+            inputs: vec![],
+            outputs: vec![],
+        }
+    }
+
+    /// add an input worth `amount` paying to `internal` tweaked by the tree
+    /// of `leaves`, key path only if there are none
+    pub(crate) fn taproot_input(
+        mut self,
+        internal: &(XOnlyPublicKey, KeySource),
+        leaves: &[Leaf],
+        amount: u64,
+    ) -> Self {
+        self.inputs
+            .push(taproot_input(self.secp, internal, leaves, amount));
+        self
+    }
+
+    /// add an output, defaults to a single 1000 sat output if none are added
+    pub(crate) fn output(mut self, value: u64, script_pubkey: Script) -> Self {
+        self.outputs.push(TxOut {
+            value,
+            script_pubkey,
+        });
+        self
+    }
+
+    pub(crate) fn build(self) -> PartiallySignedTransaction {
+        let mut outputs = self.outputs;
+        if outputs.is_empty() {
+            outputs.push(TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            });
+        }
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: (0..self.inputs.len())
+                .map(|i| TxIn {
+                    previous_output: OutPoint::new(Txid::default(), i as u32),
+                    script_sig: Script::new(),
+                    sequence: 0xffff_ffff,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: outputs,
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        psbt.inputs = self.inputs;
+        psbt
+    }
+}
+
+/// A taproot input worth `amount` with its `witness_utxo`, internal key,
+/// merkle root, leaf scripts, and every key origin filled in
+pub(crate) fn taproot_input<C: Verification>(
+    secp: &Secp256k1<C>,
+    internal: &(XOnlyPublicKey, KeySource),
+    leaves: &[Leaf],
+    amount: u64,
+) -> bitcoin::psbt::Input {
+    let mut input = bitcoin::psbt::Input::default();
+    input
+        .tap_key_origins
+        .insert(internal.0, (vec![], internal.1.clone()));
+    let info = match huffman_tree(leaves) {
+        None => TaprootSpendInfo::new_key_spend(secp, internal.0, None),
+        Some(root) => TaprootSpendInfo::from_node_info(secp, internal.0, root),
+    };
+    for leaf in leaves {
+        let ver = leaf.version;
+        let tlh = TapLeafHash::from_script(&leaf.script, ver);
+        let cb = info.control_block(&(leaf.script.clone(), ver)).unwrap();
+        input.tap_scripts.insert(cb, (leaf.script.clone(), ver));
+        for (pk, source) in &leaf.signers {
+            input
+                .tap_key_origins
+                .entry(*pk)
+                .or_insert_with(|| (vec![], source.clone()))
+                .0
+                .push(tlh);
+        }
+    }
+    input.witness_utxo = Some(TxOut {
+        value: amount,
+        script_pubkey: Script::new_v1_p2tr_tweaked(info.output_key()),
+    });
+    input.tap_internal_key = Some(internal.0);
+    input.tap_merkle_root = info.merkle_root();
+    input
+}
+
+/// The tree [`TaprootSpendInfo::with_huffman_tree`] builds, but keeping each
+/// leaf's version, which it would set to `TapScript`
+fn huffman_tree(leaves: &[Leaf]) -> Option<NodeInfo> {
+    let mut nodes: BinaryHeap<_> = leaves
+        .iter()
+        .map(|l| {
+            let node = NodeInfo::new_leaf_with_ver(l.script.clone(), l.version);
+            (Reverse(l.weight), node)
+        })
+        .collect();
+    while nodes.len() > 1 {
+        let (w1, a) = nodes.pop()?;
+        let (w2, b) = nodes.pop()?;
+        let node = NodeInfo::combine(a, b).unwrap();
+        nodes.push((Reverse(w1.0.saturating_add(w2.0)), node));
+    }
+    nodes.pop().map(|(_, node)| node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    #[test]
+    fn key_path_psbt() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let internal = derive(&secp, &ours, "m/0");
+        let psbt = PsbtBuilder::new(&secp)
+            .taproot_input(&internal, &[], 50_000)
+            .output(40_000, Script::new())
+            .build();
+        assert_eq!(psbt.unsigned_tx.input.len(), 1);
+        assert_eq!(psbt.unsigned_tx.output[0].value, 40_000);
+        let input = &psbt.inputs[0];
+        let utxo = input.witness_utxo.as_ref().unwrap();
+        assert_eq!(utxo.value, 50_000);
+        assert_eq!(
+            utxo.script_pubkey,
+            Script::new_v1_p2tr(&secp, internal.0, None)
+        );
+        assert_eq!(input.tap_internal_key, Some(internal.0));
+        assert_eq!(input.tap_merkle_root, None);
+        assert!(input.tap_scripts.is_empty());
+        assert_eq!(input.tap_key_origins[&internal.0], (vec![], internal.1));
+
+        let mut psbt = psbt;
+        ours.sign_psbt_mut(&mut psbt, &secp, bitcoin::SchnorrSighashType::All)
+            .unwrap();
+        verify_psbt(&psbt, &secp).unwrap();
+        assert!(psbt.inputs[0].tap_key_sig.is_some());
+    }
+
+    #[test]
+    fn weighted_tree() {
+        let secp = Secp256k1::new();
+        let internal = derive(&secp, &test_key(1), "m/0");
+        let signers: Vec<_> = (1..=3)
+            .map(|i| derive(&secp, &test_key(2), &format!("m/{}", i)))
+            .collect();
+        let leaves: Vec<_> = signers
+            .iter()
+            .enumerate()
+            .map(|(i, s)| Leaf {
+                weight: i as u32 + 1,
+                script: checksig_script(&s.0),
+                version: LeafVersion::TapScript,
+                signers: vec![s.clone()],
+            })
+            .collect();
+        let psbt = PsbtBuilder::new(&secp)
+            .taproot_input(&internal, &leaves, 10_000)
+            .build();
+        let input = &psbt.inputs[0];
+        assert!(input.tap_merkle_root.is_some());
+        assert_eq!(input.tap_scripts.len(), 3);
+        // the heaviest leaf sits closest to the root
+        let depth = |script: &Script| {
+            let (cb, _) = input
+                .tap_scripts
+                .iter()
+                .find(|(_, (s, _))| s == script)
+                .unwrap();
+            cb.merkle_branch.as_inner().len()
+        };
+        assert!(depth(&leaves[2].script) < depth(&leaves[0].script));
+        for s in &signers {
+            assert_eq!(input.tap_key_origins[&s.0].0.len(), 1);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Signing taproot inputs with a single private key, such as one imported
+//! from WIF, for setups with no key hierarchy.
+use super::*;
+use bitcoin::util::sighash::SighashCache;
+use bitcoin::PrivateKey;
+
+/// One private key to sign with, rather than a set of masters.
+///
+/// With no chain code there is nothing to derive, so key origins are only
+/// used to find the leaves the key signs for. The key path is signed when
+/// `tap_internal_key` is the key itself, and a leaf when `tap_key_origins`
+/// lists it for the key, whatever the origin claims. Inputs which are not
+/// taproot are left alone.
+///
+/// As with [`SigningKey`], the secret is erased on drop on a best effort
+/// basis only.
+pub struct FlatSigningKey(pub PrivateKey);
+
+impl Drop for FlatSigningKey {
+    fn drop(&mut self) {
+        self.0.inner.non_secure_erase();
+    }
+}
+
+impl FlatSigningKey {
+    /// Read a key in Wallet Import Format
+    pub fn from_wif(wif: &str) -> Result<Self, KeyError> {
+        PrivateKey::from_wif(wif)
+            .map(FlatSigningKey)
+            .map_err(KeyError::Wif)
+    }
+
+    /// The x-only key a `tap_internal_key` or leaf must name to be signed
+    pub fn x_only_public_key<C: Signing>(&self, secp: &Secp256k1<C>) -> XOnlyPublicKey {
+        self.0.inner.x_only_public_key(secp).0
+    }
+
+    /// Sign every taproot input the key matches, see [`FlatSigningKey`]
+    pub fn sign_psbt_mut<C: Signing + Verification>(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        secp: &Secp256k1<C>,
+        hash_ty: bitcoin::SchnorrSighashType,
+    ) -> Result<(), PSBTSigningError> {
+        check_psbt(psbt)?;
+        let tx = psbt.clone().extract_tx();
+        let utxos = witness_utxos(psbt);
+        let mut ctx = TxContext::new(&tx, &utxos, &psbt.xpub);
+        for (idx, input) in psbt.inputs.iter_mut().enumerate() {
+            self.sign_input_in_context(secp, &mut ctx, idx, input, hash_ty)?;
+        }
+        Ok(())
+    }
+
+    /// Sign input `idx` if the key matches it
+    pub fn sign_psbt_input_mut<C: Signing + Verification>(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        secp: &Secp256k1<C>,
+        idx: usize,
+        hash_ty: bitcoin::SchnorrSighashType,
+    ) -> Result<(), PSBTSigningError> {
+        self.sign_psbt_input_with_summary_mut(psbt, secp, idx, hash_ty)
+            .map(|_| ())
+    }
+
+    /// Same as [`FlatSigningKey::sign_psbt_input_mut`], but reports which
+    /// signatures were added. Leaves which already have our signature are
+    /// kept as they are.
+    pub fn sign_psbt_input_with_summary_mut<C: Signing + Verification>(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        secp: &Secp256k1<C>,
+        idx: usize,
+        hash_ty: bitcoin::SchnorrSighashType,
+    ) -> Result<InputSignatures, PSBTSigningError> {
+        check_psbt(psbt)?;
+        let tx = psbt.clone().extract_tx();
+        let utxos = witness_utxos(psbt);
+        let mut ctx = TxContext::new(&tx, &utxos, &psbt.xpub);
+        let input = psbt
+            .inputs
+            .get_mut(idx)
+            .ok_or(PSBTSigningError::NoInputAtIndex(idx))?;
+        self.sign_input_in_context(secp, &mut ctx, idx, input, hash_ty)
+    }
+
+    /// Signs `input`, which is input `idx` of `ctx`'s transaction, so that a
+    /// PSBT is only checked once however many of its inputs are signed
+    fn sign_input_in_context<C: Signing + Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        ctx: &mut TxContext<'_>,
+        idx: usize,
+        input: &mut bitcoin::psbt::Input,
+        hash_ty: bitcoin::SchnorrSighashType,
+    ) -> Result<InputSignatures, PSBTSigningError> {
+        if !ecdsa::is_taproot_input(input) {
+            return Ok(InputSignatures::default());
+        }
+        if is_sighash_single(hash_ty) && idx >= ctx.tx.output.len() {
+            return Err(PSBTSigningError::SingleWithoutOutput { index: idx });
+        }
+        let utxos = ctx.utxos.as_ref().map_err(Clone::clone)?;
+        let prevouts = Prevouts::All(utxos);
+        let mut kp = KeyPair::from_secret_key(secp, &self.0.inner);
+        let signed = sign_input(secp, &kp, idx, input, &mut ctx.sighash, &prevouts, hash_ty);
+        kp.non_secure_erase();
+        signed
+    }
+}
+
+fn sign_input<C: Signing + Verification>(
+    secp: &Secp256k1<C>,
+    kp: &KeyPair,
+    idx: usize,
+    input: &mut bitcoin::psbt::Input,
+    sighash: &mut SighashCache<&bitcoin::Transaction>,
+    prevouts: &Prevouts<TxOut>,
+    hash_ty: bitcoin::SchnorrSighashType,
+) -> Result<InputSignatures, PSBTSigningError> {
+    let opts = SigningOptions::default();
+    let ours = kp.x_only_public_key().0;
+    let mut signed = InputSignatures::default();
+    if input.tap_internal_key == Some(ours) {
+        let mut tweaked = kp.tap_tweak(secp, input.tap_merkle_root).into_inner();
+        let (output_key, _) = tweaked.x_only_public_key();
+        let expected =
+            Script::new_v1_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(output_key));
+        let sig = match prevout_at(prevouts, idx) {
+            Some(utxo) if utxo.script_pubkey == expected => get_sig(
+                sighash, idx, prevouts, hash_ty, secp, &tweaked, &None, &opts,
+            ),
+            Some(utxo) => Err(PSBTSigningError::OutputKeyMismatch {
+                index: idx,
+                script_pubkey: utxo.script_pubkey.clone(),
+                tweaked: output_key,
+            }),
+            None => Err(PSBTSigningError::NoUTXOAtIndex(idx)),
+        };
+        tweaked.non_secure_erase();
+        input.tap_key_sig = Some(sig?);
+        signed.key_path_signed = true;
+    }
+    let leaves = match input.tap_key_origins.get(&ours) {
+        Some((leaves, _)) => leaves.clone(),
+        None => vec![],
+    };
+    for (i, leaf) in leaves.iter().enumerate() {
+        if leaves[..i].contains(leaf) || input.tap_script_sigs.contains_key(&(ours, *leaf)) {
+            continue;
+        }
+        let path = Some((*leaf, DEFAULT_CODESEP));
+        let sig = get_sig(sighash, idx, prevouts, hash_ty, secp, kp, &path, &opts)?;
+        input.tap_script_sigs.insert((ours, *leaf), sig);
+        signed.script_sigs_added += 1;
+    }
+    Ok(signed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+    use bitcoin::util::taproot::LeafVersion;
+
+    #[test]
+    fn sign_with_wif() {
+        let secp = Secp256k1::new();
+        let secret = bitcoin::secp256k1::SecretKey::from_slice(&[0x11; 32]).unwrap();
+        let wif = PrivateKey::new(secret, Network::Regtest).to_wif();
+        assert!(matches!(
+            FlatSigningKey::from_wif("not a wif"),
+            Err(KeyError::Wif(_))
+        ));
+        let flat = FlatSigningKey::from_wif(&wif).unwrap();
+        let pk = flat.x_only_public_key(&secp);
+        assert_eq!(pk, secret.x_only_public_key(&secp).0);
+
+        // a bare key has no origin to speak of
+        let origin = (Fingerprint::default(), DerivationPath::master());
+        let other = derive(&secp, &test_key(2), "m/0");
+        let mut psbt = unsigned_psbt(3);
+        psbt.inputs[0] = taproot_input(&secp, &(pk, origin.clone()), &[]);
+        psbt.inputs[1] = taproot_input(&secp, &other, &[(pk, origin)]);
+        psbt.inputs[2] = taproot_input(&secp, &other, &[]);
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        let summary = flat
+            .sign_psbt_input_with_summary_mut(&mut psbt, &secp, 0, hash_ty)
+            .unwrap();
+        assert!(summary.key_path_signed && summary.script_sigs_added == 0);
+        flat.sign_psbt_mut(&mut psbt, &secp, hash_ty).unwrap();
+        verify_psbt(&psbt, &secp).unwrap();
+        let leaf = TapLeafHash::from_script(&checksig_script(&pk), LeafVersion::TapScript);
+        assert!(psbt.inputs[1].tap_key_sig.is_none());
+        assert!(psbt.inputs[1].tap_script_sigs.contains_key(&(pk, leaf)));
+        assert_eq!(psbt.inputs[2], taproot_input(&secp, &other, &[]));
+    }
+}
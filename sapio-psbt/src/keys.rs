@@ -0,0 +1,882 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Alternative ways of creating and loading a [`SigningKey`].
+use super::*;
+pub use bip39::Mnemonic;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::util::bip32::ChildNumber;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use miniscript::descriptor::{
+    Descriptor, DescriptorPublicKey, DescriptorSecretKey, DescriptorXKey, Wildcard,
+};
+#[cfg(feature = "native")]
+use std::path::Path;
+use std::str::FromStr;
+#[cfg(feature = "native")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+/// Leading bytes of an encrypted key file. A plaintext key file is exactly
+/// one 78 byte encoded xpriv, so it can never start with these.
+const ENCRYPTED_MAGIC: &[u8; 8] = b"sapiokey";
+const ENCRYPTED_VERSION: u8 = 1;
+/// magic, version, log_n, r, p, salt, nonce
+const ENCRYPTED_HEADER_LEN: usize = 8 + 1 + 1 + 4 + 4 + 16 + 12;
+const XPRIV_LEN: usize = 78;
+
+/// scrypt cost parameters for the passphrase derived encryption key
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct KdfParams {
+    log_n: u8,
+    r: u32,
+    p: u32,
+}
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            log_n: scrypt::Params::RECOMMENDED_LOG_N,
+            r: scrypt::Params::RECOMMENDED_R,
+            p: scrypt::Params::RECOMMENDED_P,
+        }
+    }
+}
+/// The costliest scrypt parameters a version 1 key file may ask for. They
+/// are read from the header before the passphrase can be checked, so a
+/// crafted file could otherwise demand any amount of memory and time.
+const MAX_KDF: KdfParams = KdfParams {
+    log_n: 20,
+    r: 8,
+    p: 1,
+};
+
+impl KdfParams {
+    fn within(&self, max: &KdfParams) -> bool {
+        self.log_n <= max.log_n && self.r <= max.r && self.p <= max.p
+    }
+    fn derive_key(&self, passphrase: &str, salt: &[u8]) -> Result<Key, KeyError> {
+        let params = scrypt::Params::new(self.log_n, self.r, self.p, 32)
+            .map_err(|_| KeyError::MalformedEncryptedKey)?;
+        let mut key = Key::default();
+        scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key)
+            .expect("32 bytes is a valid output length");
+        Ok(key)
+    }
+}
+
+/// How a plaintext key file is written. Readers accept either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFormat {
+    /// the 78 byte BIP32 serialization
+    Raw,
+    /// the `xprv...` string
+    Base58,
+}
+
+impl Default for KeyFormat {
+    fn default() -> Self {
+        KeyFormat::Raw
+    }
+}
+
+impl FromStr for KeyFormat {
+    type Err = KeyError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(KeyFormat::Raw),
+            "base58" => Ok(KeyFormat::Base58),
+            _ => Err(KeyError::UnknownKeyFormat(s.into())),
+        }
+    }
+}
+
+/// The kind of output descriptor [`SigningKey::descriptor`] emits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTemplate {
+    /// `tr(KEY)`, a key path only taproot output
+    Tr,
+    /// `wpkh(KEY)`
+    Wpkh,
+}
+
+impl FromStr for DescriptorTemplate {
+    type Err = KeyError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tr" => Ok(DescriptorTemplate::Tr),
+            "wpkh" => Ok(DescriptorTemplate::Wpkh),
+            _ => Err(KeyError::UnknownDescriptorTemplate(s.into())),
+        }
+    }
+}
+
+/// A key imported from a line of a Bitcoin Core wallet backup, see
+/// [`SigningKey::from_core_line`]
+pub struct CoreWalletKey {
+    pub key: SigningKey,
+    /// where `key` sits in its wallet, its own fingerprint and the empty
+    /// path if it is a master key
+    pub origin: KeySource,
+    /// the derivation below `key` shared by every address, e.g. `0` in
+    /// `tprv.../0/*`
+    pub path: DerivationPath,
+    /// whether each address is a further child of `path`, as marked by a
+    /// trailing `/*` or `/*h`
+    pub wildcard: Wildcard,
+    /// the descriptor with its xpriv replaced by the xpub, safe to show or
+    /// import elsewhere. A `dumpwallet` master key has none.
+    pub descriptor: Option<Descriptor<DescriptorPublicKey>>,
+}
+
+impl CoreWalletKey {
+    /// The key origin of the address at `index`, which must be given for a
+    /// ranged descriptor and only for one
+    pub fn key_origin(&self, index: Option<u32>) -> Result<KeySource, KeyError> {
+        let path = self.origin.1.extend(&self.path);
+        let child = match (self.wildcard, index) {
+            (Wildcard::None, None) => return Ok((self.origin.0, path)),
+            (Wildcard::Unhardened, Some(index)) => ChildNumber::from_normal_idx(index)?,
+            (Wildcard::Hardened, Some(index)) => ChildNumber::from_hardened_idx(index)?,
+            (wildcard, _) => {
+                return Err(KeyError::DescriptorIndex {
+                    ranged: wildcard != Wildcard::None,
+                })
+            }
+        };
+        Ok((self.origin.0, path.child(child)))
+    }
+    /// A signer for the address at `index` only, and the options it must be
+    /// used with. This is the key derived down to the address, which signs
+    /// for that address's key origin through
+    /// [`SigningOptions::account_origins`].
+    pub fn signer_at<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        index: Option<u32>,
+    ) -> Result<(SigningKey, SigningOptions), KeyError> {
+        let origin = self.key_origin(index)?;
+        let below = &origin.1[self.origin.1.len()..];
+        let signer = self.key.derive_child(secp, &below.into())?;
+        let mut opts = SigningOptions::default();
+        opts.account_origins
+            .insert(signer.0[0].fingerprint(secp), origin);
+        Ok((signer, opts))
+    }
+}
+
+/// Errors from creating or loading a [`SigningKey`]
+#[derive(Debug)]
+pub enum KeyError {
+    Bip32(bitcoin::util::bip32::Error),
+    /// the phrase had a bad word count, an unknown word, or a bad checksum
+    Mnemonic(bip39::Error),
+    Io(std::io::Error),
+    /// the key is encrypted and must be read with a passphrase
+    EncryptedKey,
+    /// the key was expected to be encrypted but is plaintext
+    NotEncrypted,
+    /// the encrypted key has a corrupt header or length
+    MalformedEncryptedKey,
+    UnsupportedEncryptionVersion(u8),
+    /// decryption failed, either the passphrase is wrong or the data is corrupt
+    WrongPassphrase,
+    /// the key file asks for costlier scrypt parameters than its version
+    /// allows, so it was not decrypted
+    KdfTooCostly {
+        log_n: u8,
+        r: u32,
+        p: u32,
+    },
+    /// not one of the [`KeyFormat`] names
+    UnknownKeyFormat(String),
+    /// not one of the [`DescriptorTemplate`] names
+    UnknownDescriptorTemplate(String),
+    /// the environment variable named is unset, or is not unicode
+    EnvVarUnset(String),
+    /// the environment variable named holds neither a base58 `xprv` nor a
+    /// hex encoded xpriv. The value is left out as it may be a secret.
+    MalformedEnvKey(String),
+    /// the line is neither a descriptor nor a `dumpwallet` master key line.
+    /// The line is left out as it may hold a secret.
+    MalformedCoreLine,
+    /// the descriptor must hold exactly one xpriv, rather than this many
+    DescriptorKeyCount(usize),
+    /// a ranged descriptor needs an address index, and an unranged one must
+    /// not be given one
+    DescriptorIndex {
+        ranged: bool,
+    },
+    /// the string is not a WIF private key
+    Wif(bitcoin::util::key::Error),
+}
+
+impl Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for KeyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KeyError::Bip32(e) => Some(e),
+            KeyError::Mnemonic(e) => Some(e),
+            KeyError::Io(e) => Some(e),
+            KeyError::Wif(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<bitcoin::util::bip32::Error> for KeyError {
+    fn from(e: bitcoin::util::bip32::Error) -> Self {
+        KeyError::Bip32(e)
+    }
+}
+impl From<bip39::Error> for KeyError {
+    fn from(e: bip39::Error) -> Self {
+        KeyError::Mnemonic(e)
+    }
+}
+impl From<std::io::Error> for KeyError {
+    fn from(e: std::io::Error) -> Self {
+        KeyError::Io(e)
+    }
+}
+
+impl SigningKey {
+    /// Derive the BIP32 master key from a BIP39 phrase and passphrase
+    pub fn from_mnemonic(
+        phrase: &str,
+        passphrase: &str,
+        network: Network,
+    ) -> Result<Self, KeyError> {
+        Self::from_parsed_mnemonic(&Mnemonic::parse(phrase)?, passphrase, network)
+    }
+    /// Generate a fresh 24 word phrase, returning it along with the derived
+    /// master key
+    #[cfg(feature = "native")]
+    pub fn new_mnemonic(passphrase: &str, network: Network) -> Result<(Mnemonic, Self), KeyError> {
+        let entropy: [u8; 32] = rand::thread_rng().gen();
+        let mnemonic = Mnemonic::from_entropy(&entropy)?;
+        let key = Self::from_parsed_mnemonic(&mnemonic, passphrase, network)?;
+        Ok((mnemonic, key))
+    }
+    /// Read a plaintext key file, as written by the cli's `signer new`
+    #[cfg(feature = "native")]
+    pub async fn read_key_from_file(file: impl AsRef<Path>) -> Result<Self, KeyError> {
+        Self::read_plaintext_key(&tokio::fs::read(file).await?)
+    }
+    /// Read a plaintext key from `reader` until EOF, either as the 78 raw
+    /// bytes or as a base58 `xprv` string
+    #[cfg(feature = "native")]
+    pub async fn read_key_from_reader(
+        mut reader: impl AsyncRead + Unpin,
+    ) -> Result<Self, KeyError> {
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).await?;
+        Self::read_plaintext_key(&buf)
+    }
+    /// [`SigningKey::read_key_from_reader`] on stdin, so the key never has to
+    /// touch the disk
+    #[cfg(feature = "native")]
+    pub async fn read_key_from_stdin() -> Result<Self, KeyError> {
+        Self::read_key_from_reader(tokio::io::stdin()).await
+    }
+    /// Read a key from the environment variable `var_name`, as a base58
+    /// `xprv` string or the raw encoding in hex, so containers can be handed
+    /// a key without it being written to their filesystem
+    pub fn read_key_from_env(var_name: &str) -> Result<Self, KeyError> {
+        let value = std::env::var(var_name).map_err(|_| KeyError::EnvVarUnset(var_name.into()))?;
+        let value = value.trim();
+        let malformed = KeyError::MalformedEnvKey(var_name.into());
+        if value.len() == 2 * XPRIV_LEN {
+            let mut raw = match Vec::<u8>::from_hex(value) {
+                Ok(raw) => raw,
+                Err(_) => return Err(malformed),
+            };
+            let key = Self::read_key_from_buf(&raw);
+            raw.fill(0);
+            return key.map_err(|_| malformed);
+        }
+        ExtendedPrivKey::from_str(value)
+            .map(|k| SigningKey(vec![k]))
+            .map_err(|_| malformed)
+    }
+    /// Import the key from a line of a Bitcoin Core wallet backup: a private
+    /// descriptor as printed by `listdescriptors true`, such as
+    /// `tr(tprv.../86h/1h/0h/0/*)#checksum`, or the
+    /// `# extended private masterkey: xprv...` line of a `dumpwallet` file.
+    pub fn from_core_line<C: Signing>(
+        secp: &Secp256k1<C>,
+        line: &str,
+    ) -> Result<CoreWalletKey, KeyError> {
+        let line = line.trim();
+        if let Some(xprv) = line.strip_prefix("# extended private masterkey:") {
+            let key =
+                ExtendedPrivKey::from_str(xprv.trim()).map_err(|_| KeyError::MalformedCoreLine)?;
+            return Ok(CoreWalletKey {
+                origin: (key.fingerprint(secp), DerivationPath::master()),
+                key: SigningKey(vec![key]),
+                path: DerivationPath::master(),
+                wildcard: Wildcard::None,
+                descriptor: None,
+            });
+        }
+        let (descriptor, keys) =
+            Descriptor::parse_descriptor(secp, line).map_err(|_| KeyError::MalformedCoreLine)?;
+        let xprvs: Vec<_> = keys
+            .values()
+            .filter_map(|k| match k {
+                DescriptorSecretKey::XPrv(x) => Some(x),
+                DescriptorSecretKey::SinglePriv(_) => None,
+            })
+            .collect();
+        let xprv = match xprvs[..] {
+            [xprv] if keys.len() == 1 => xprv,
+            _ => return Err(KeyError::DescriptorKeyCount(keys.len())),
+        };
+        Ok(CoreWalletKey {
+            key: SigningKey(vec![xprv.xkey]),
+            origin: xprv
+                .origin
+                .clone()
+                .unwrap_or_else(|| (xprv.xkey.fingerprint(secp), DerivationPath::master())),
+            path: xprv.derivation_path.clone(),
+            wildcard: xprv.wildcard,
+            descriptor: Some(descriptor),
+        })
+    }
+    /// Parse a plaintext key, either the raw encoding or base58 strings
+    pub(crate) fn read_plaintext_key(buf: &[u8]) -> Result<Self, KeyError> {
+        if buf.starts_with(ENCRYPTED_MAGIC) {
+            return Err(KeyError::EncryptedKey);
+        }
+        // a raw encoding starts with a 0x04 version byte, so is never text
+        if buf
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || b.is_ascii_whitespace())
+        {
+            let text = std::str::from_utf8(buf).expect("checked ascii");
+            return Self::from_base58(text);
+        }
+        Ok(Self::read_key_from_buf(buf)?)
+    }
+    /// Parse whitespace separated base58 `xprv` (or `tprv`) strings, as
+    /// written by [`SigningKey::to_base58`]
+    pub fn from_base58(text: &str) -> Result<Self, KeyError> {
+        let keys = text
+            .split_whitespace()
+            .map(ExtendedPrivKey::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        if keys.is_empty() {
+            return Err(bitcoin::util::bip32::Error::WrongExtendedKeyLength(0).into());
+        }
+        Ok(SigningKey(keys))
+    }
+    /// Every held key as a base58 string, one per line
+    pub fn to_base58(&self) -> String {
+        self.0
+            .iter()
+            .map(|k| k.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+    /// Encode the held keys for writing to a key file. Only a single key can
+    /// be written as [`KeyFormat::Raw`]; any others are dropped.
+    pub fn encode_as(&self, format: KeyFormat) -> Vec<u8> {
+        match format {
+            KeyFormat::Raw => self.0[0].encode().to_vec(),
+            KeyFormat::Base58 => (self.to_base58() + "\n").into_bytes(),
+        }
+    }
+    /// The output descriptor, e.g. `tr([fingerprint/path]xpub/*)`, for the
+    /// account at `path` below our first master key, to import the watch
+    /// only wallet into Bitcoin Core or other wallets. Panics if there are no
+    /// keys.
+    pub fn descriptor<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        path: &DerivationPath,
+        template: DescriptorTemplate,
+    ) -> Result<Descriptor<DescriptorPublicKey>, KeyError> {
+        let master = &self.0[0];
+        let account = master.derive_priv(secp, path)?;
+        let key = DescriptorPublicKey::XPub(DescriptorXKey {
+            origin: Some((master.fingerprint(secp), path.clone())),
+            xkey: ExtendedPubKey::from_priv(secp, &account),
+            derivation_path: DerivationPath::master(),
+            wildcard: Wildcard::Unhardened,
+        });
+        Ok(match template {
+            DescriptorTemplate::Tr => Descriptor::new_tr(key, None),
+            DescriptorTemplate::Wpkh => Descriptor::new_wpkh(key),
+        }
+        .expect("xpubs are always compressed"))
+    }
+    /// Create `count` independent master keys for a test wallet, writing the
+    /// `i`th to `out_dir/key_<i>` as a raw key file, and return their xpubs
+    /// in order. `out_dir` is created if it does not exist, and an existing
+    /// `key_<i>` is an error rather than being overwritten.
+    #[cfg(feature = "native")]
+    pub async fn new_keys(
+        network: Network,
+        count: usize,
+        out_dir: impl AsRef<Path>,
+    ) -> Result<Vec<ExtendedPubKey>, KeyError> {
+        let secp = crate::global_secp();
+        tokio::fs::create_dir_all(&out_dir).await?;
+        let mut xpubs = Vec::with_capacity(count);
+        for i in 0..count {
+            let key = Self::new_key(network)?;
+            let path = out_dir.as_ref().join(format!("key_{}", i));
+            let mut file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .await?;
+            file.write_all(&key.encode_as(KeyFormat::Raw)).await?;
+            xpubs.push(key.pubkey(secp)[0]);
+        }
+        Ok(xpubs)
+    }
+    /// Create a new master key, writing it to `out` encrypted under
+    /// `passphrase`
+    #[cfg(feature = "native")]
+    pub async fn new_key_encrypted(
+        network: Network,
+        out: impl AsRef<Path>,
+        passphrase: &str,
+    ) -> Result<Self, KeyError> {
+        let key = Self::new_key(network)?;
+        tokio::fs::write(out, key.encrypt(passphrase)?).await?;
+        Ok(key)
+    }
+    /// Read a key file written by [`SigningKey::new_key_encrypted`]
+    #[cfg(feature = "native")]
+    pub async fn read_encrypted_key_from_file(
+        file: impl AsRef<Path>,
+        passphrase: &str,
+    ) -> Result<Self, KeyError> {
+        let buf = tokio::fs::read(file).await?;
+        Self::read_encrypted_key_from_buf(&buf[..], passphrase)
+    }
+    /// Encrypt all of the held keys with a scrypt derived ChaCha20Poly1305 key
+    #[cfg(feature = "native")]
+    pub fn encrypt(&self, passphrase: &str) -> Result<Vec<u8>, KeyError> {
+        self.encrypt_with(passphrase, KdfParams::default())
+    }
+    #[cfg(feature = "native")]
+    pub(crate) fn encrypt_with(
+        &self,
+        passphrase: &str,
+        kdf: KdfParams,
+    ) -> Result<Vec<u8>, KeyError> {
+        let salt: [u8; 16] = rand::thread_rng().gen();
+        let nonce: [u8; 12] = rand::thread_rng().gen();
+        let mut out = Vec::with_capacity(ENCRYPTED_HEADER_LEN + self.0.len() * XPRIV_LEN + 16);
+        out.extend_from_slice(ENCRYPTED_MAGIC);
+        out.push(ENCRYPTED_VERSION);
+        out.push(kdf.log_n);
+        out.extend_from_slice(&kdf.r.to_be_bytes());
+        out.extend_from_slice(&kdf.p.to_be_bytes());
+        out.extend_from_slice(&salt);
+        out.extend_from_slice(&nonce);
+        let plaintext: Vec<u8> = self.0.iter().flat_map(|k| k.encode()).collect();
+        let cipher = ChaCha20Poly1305::new(&kdf.derive_key(passphrase, &salt)?);
+        let ciphertext = cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &out,
+                },
+            )
+            .expect("encryption cannot fail for in-memory buffers");
+        out.extend(ciphertext);
+        Ok(out)
+    }
+    /// Decrypt the output of [`SigningKey::encrypt`]
+    pub fn read_encrypted_key_from_buf(buf: &[u8], passphrase: &str) -> Result<Self, KeyError> {
+        if !buf.starts_with(ENCRYPTED_MAGIC) {
+            return Err(KeyError::NotEncrypted);
+        }
+        if buf.len() < ENCRYPTED_HEADER_LEN {
+            return Err(KeyError::MalformedEncryptedKey);
+        }
+        let (header, ciphertext) = buf.split_at(ENCRYPTED_HEADER_LEN);
+        if header[8] != ENCRYPTED_VERSION {
+            return Err(KeyError::UnsupportedEncryptionVersion(header[8]));
+        }
+        let kdf = KdfParams {
+            log_n: header[9],
+            r: u32::from_be_bytes(header[10..14].try_into().expect("4 bytes")),
+            p: u32::from_be_bytes(header[14..18].try_into().expect("4 bytes")),
+        };
+        if !kdf.within(&MAX_KDF) {
+            return Err(KeyError::KdfTooCostly {
+                log_n: kdf.log_n,
+                r: kdf.r,
+                p: kdf.p,
+            });
+        }
+        let salt = &header[18..34];
+        let nonce = Nonce::from_slice(&header[34..46]);
+        let cipher = ChaCha20Poly1305::new(&kdf.derive_key(passphrase, salt)?);
+        let plaintext = cipher
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| KeyError::WrongPassphrase)?;
+        if plaintext.is_empty() || plaintext.len() % XPRIV_LEN != 0 {
+            return Err(KeyError::MalformedEncryptedKey);
+        }
+        let keys = plaintext
+            .chunks(XPRIV_LEN)
+            .map(ExtendedPrivKey::decode)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(SigningKey(keys))
+    }
+    fn from_parsed_mnemonic(
+        mnemonic: &Mnemonic,
+        passphrase: &str,
+        network: Network,
+    ) -> Result<Self, KeyError> {
+        let seed = mnemonic.to_seed(passphrase);
+        Ok(SigningKey(vec![ExtendedPrivKey::new_master(
+            network, &seed,
+        )?]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// cheap scrypt parameters so debug-mode tests stay fast
+    #[cfg(feature = "native")]
+    const TEST_KDF: KdfParams = KdfParams {
+        log_n: 4,
+        r: 8,
+        p: 1,
+    };
+
+    #[cfg(feature = "native")]
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("sapio-psbt-{}-{}", std::process::id(), name))
+    }
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn encrypted_key_file_round_trip() {
+        let path = temp_path("encrypted");
+        let key = SigningKey::new_key(Network::Testnet).unwrap();
+        tokio::fs::write(&path, key.encrypt_with("hunter2", TEST_KDF).unwrap())
+            .await
+            .unwrap();
+        let read = SigningKey::read_encrypted_key_from_file(&path, "hunter2")
+            .await
+            .unwrap();
+        assert_eq!(key.0, read.0);
+        // the plaintext reader refuses the encrypted file
+        assert!(matches!(
+            SigningKey::read_key_from_file(&path).await,
+            Err(KeyError::EncryptedKey)
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn key_from_env() {
+        let key = crate::tests::test_key(1);
+        let var = "SAPIO_PSBT_TEST_KEY_FROM_ENV";
+        // regtest keys encode as testnet, so compare encodings
+        let read = || SigningKey::read_key_from_env(var).ok().unwrap().0[0];
+        assert!(matches!(
+            SigningKey::read_key_from_env(var),
+            Err(KeyError::EnvVarUnset(v)) if v == var
+        ));
+        std::env::set_var(var, format!("{}\n", key.0[0]));
+        assert_eq!(read().encode(), key.0[0].encode());
+        std::env::set_var(
+            var,
+            bitcoin::hashes::hex::ToHex::to_hex(&key.0[0].encode()[..]),
+        );
+        assert_eq!(read().encode(), key.0[0].encode());
+        for bad in ["xprvnotakey", &"z".repeat(2 * XPRIV_LEN)] {
+            std::env::set_var(var, bad);
+            let e = match SigningKey::read_key_from_env(var) {
+                Err(e) => e,
+                Ok(_) => panic!("{} should not parse", bad),
+            };
+            assert!(matches!(&e, KeyError::MalformedEnvKey(v) if v == var));
+            assert!(!e.to_string().contains(bad));
+        }
+        std::env::remove_var(var);
+    }
+
+    #[test]
+    fn descriptors_parse() {
+        use miniscript::DescriptorTrait;
+        let secp = Secp256k1::new();
+        let key = SigningKey::from_seed_bytes([3; 32], Network::Testnet).unwrap();
+        let path = DerivationPath::from_str("m/86'/1'/0'").unwrap();
+        let fingerprint = key.0[0].fingerprint(&secp);
+        for (template, prefix) in [
+            (DescriptorTemplate::Tr, "tr("),
+            (DescriptorTemplate::Wpkh, "wpkh("),
+        ] {
+            let desc = key.descriptor(&secp, &path, template).unwrap().to_string();
+            assert!(desc.starts_with(&format!("{}[{}/86'/1'/0']tpub", prefix, fingerprint)));
+            assert!(desc.contains("/*)#"));
+            let parsed = Descriptor::<DescriptorPublicKey>::from_str(&desc).unwrap();
+            assert_eq!(parsed.to_string(), desc);
+            // the first address is for the account key's child 0
+            let account = key.0[0].derive_priv(&secp, &path).unwrap();
+            let child = ExtendedPubKey::from_priv(&secp, &account)
+                .ckd_pub(&secp, bitcoin::util::bip32::ChildNumber::from(0))
+                .unwrap();
+            let first = parsed
+                .derived_descriptor(&secp, 0)
+                .unwrap()
+                .address(Network::Testnet)
+                .unwrap();
+            let expected = match template {
+                DescriptorTemplate::Tr => {
+                    bitcoin::Address::p2tr(&secp, child.to_x_only_pub(), None, Network::Testnet)
+                }
+                DescriptorTemplate::Wpkh => {
+                    bitcoin::Address::p2wpkh(&child.to_pub(), Network::Testnet).unwrap()
+                }
+            };
+            assert_eq!(first, expected);
+        }
+        assert!(DescriptorTemplate::from_str("sh").is_err());
+    }
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn bulk_new_keys() {
+        let dir = temp_path("bulk");
+        let xpubs = SigningKey::new_keys(Network::Regtest, 5, &dir)
+            .await
+            .unwrap();
+        let mut fingerprints = std::collections::BTreeSet::new();
+        for (i, xpub) in xpubs.iter().enumerate() {
+            let key = SigningKey::read_key_from_file(dir.join(format!("key_{}", i)))
+                .await
+                .unwrap();
+            // regtest keys are encoded as testnet, so compare fingerprints
+            assert_eq!(
+                key.pubkey(&Secp256k1::new())[0].fingerprint(),
+                xpub.fingerprint()
+            );
+            fingerprints.insert(xpub.fingerprint());
+        }
+        assert_eq!(fingerprints.len(), 5);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 5);
+
+        // a second run must not replace the keys already written
+        let before = std::fs::read(dir.join("key_0")).unwrap();
+        assert!(matches!(
+            SigningKey::new_keys(Network::Regtest, 5, &dir).await,
+            Err(KeyError::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists
+        ));
+        assert_eq!(std::fs::read(dir.join("key_0")).unwrap(), before);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 5);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn read_raw_and_base58() {
+        let key = SigningKey::new_key(Network::Bitcoin).unwrap();
+        let raw = key.0[0].encode();
+        let read = SigningKey::read_key_from_reader(&raw[..]).await.unwrap();
+        assert_eq!(key.0, read.0);
+        let text = format!("{}\n", key.0[0]);
+        let read = SigningKey::read_key_from_reader(text.as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(key.0, read.0);
+        assert!(matches!(
+            SigningKey::read_key_from_reader(&b"xprvnotakey"[..]).await,
+            Err(KeyError::Bip32(_))
+        ));
+        assert!(matches!(
+            SigningKey::read_key_from_reader(&raw[..40]).await,
+            Err(KeyError::Bip32(_))
+        ));
+    }
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn base58_key_file() {
+        let secp = Secp256k1::new();
+        let path = temp_path("base58");
+        let key = SigningKey::new_key(Network::Bitcoin).unwrap();
+        let xpub = key.pubkey(&secp)[0].to_string();
+        let file = key.encode_as(KeyFormat::Base58);
+        assert!(file.starts_with(b"xprv"));
+        tokio::fs::write(&path, file).await.unwrap();
+        let read = SigningKey::read_key_from_file(&path).await.unwrap();
+        assert_eq!(read.pubkey(&secp)[0].to_string(), xpub);
+        assert_eq!(SigningKey::from_base58(&read.to_base58()).unwrap().0, key.0);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut two = SigningKey::new_key(Network::Bitcoin).unwrap();
+        two.merge(SigningKey::new_key(Network::Bitcoin).unwrap());
+        assert_eq!(SigningKey::from_base58(&two.to_base58()).unwrap().0, two.0);
+        assert!(SigningKey::from_base58(" \n").is_err());
+        assert!(matches!(
+            "hex".parse::<KeyFormat>(),
+            Err(KeyError::UnknownKeyFormat(_))
+        ));
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn encrypted_key_errors() {
+        let mut key = SigningKey::new_key(Network::Testnet).unwrap();
+        key.merge(SigningKey::new_key(Network::Testnet).unwrap());
+        let enc = key.encrypt_with("hunter2", TEST_KDF).unwrap();
+        let read = SigningKey::read_encrypted_key_from_buf(&enc, "hunter2").unwrap();
+        assert_eq!(key.0, read.0);
+        assert!(matches!(
+            SigningKey::read_encrypted_key_from_buf(&enc, "hunter3"),
+            Err(KeyError::WrongPassphrase)
+        ));
+        for (at, bytes) in [(9, &[63][..]), (10, &[0, 0, 1, 0]), (14, &[0, 0, 0, 2])] {
+            let mut costly = enc.clone();
+            costly[at..at + bytes.len()].copy_from_slice(bytes);
+            assert!(matches!(
+                SigningKey::read_encrypted_key_from_buf(&costly, "hunter2"),
+                Err(KeyError::KdfTooCostly { .. })
+            ));
+        }
+        let mut tampered = enc.clone();
+        tampered[9] += 1;
+        assert!(matches!(
+            SigningKey::read_encrypted_key_from_buf(&tampered, "hunter2"),
+            Err(KeyError::WrongPassphrase)
+        ));
+        assert!(matches!(
+            SigningKey::read_encrypted_key_from_buf(&key.0[0].encode(), "hunter2"),
+            Err(KeyError::NotEncrypted)
+        ));
+        assert!(matches!(
+            SigningKey::read_encrypted_key_from_buf(&enc[..20], "hunter2"),
+            Err(KeyError::MalformedEncryptedKey)
+        ));
+    }
+
+    #[test]
+    fn bip39_test_vectors() {
+        let secp = Secp256k1::new();
+        let vectors = [
+            (
+                "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+                "xprv9s21ZrQH143K3h3fDYiay8mocZ3afhfULfb5GX8kCBdno77K4HiA15Tg23wpbeF1pLfs1c5SPmYHrEpTuuRhxMwvKDwqdKiGJS9XFKzUsAF",
+            ),
+            (
+                "legal winner thank year wave sausage worth useful legal winner thank yellow",
+                "xprv9s21ZrQH143K2gA81bYFHqU68xz1cX2APaSq5tt6MFSLeXnCKV1RVUJt9FWNTbrrryem4ZckN8k4Ls1H6nwdvDTvnV7zEXs2HgPezuVccsq",
+            ),
+        ];
+        for (phrase, xprv) in vectors {
+            let key = SigningKey::from_mnemonic(phrase, "TREZOR", Network::Bitcoin).unwrap();
+            let expected = ExtendedPrivKey::from_str(xprv).unwrap();
+            assert_eq!(key.0[0], expected);
+            assert_eq!(key.0[0].fingerprint(&secp), expected.fingerprint(&secp));
+        }
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn new_mnemonic_round_trips() {
+        let (mnemonic, key) = SigningKey::new_mnemonic("", Network::Regtest).unwrap();
+        assert_eq!(mnemonic.word_count(), 24);
+        let reloaded =
+            SigningKey::from_mnemonic(&mnemonic.to_string(), "", Network::Regtest).unwrap();
+        assert_eq!(key.0, reloaded.0);
+    }
+
+    #[test]
+    fn bad_mnemonics() {
+        let bad_checksum = ["abandon"; 12].join(" ");
+        assert!(matches!(
+            SigningKey::from_mnemonic(&bad_checksum, "", Network::Regtest),
+            Err(KeyError::Mnemonic(bip39::Error::InvalidChecksum))
+        ));
+        let unknown_word = ["abandon"; 11].join(" ") + " notaword";
+        assert!(matches!(
+            SigningKey::from_mnemonic(&unknown_word, "", Network::Regtest),
+            Err(KeyError::Mnemonic(bip39::Error::UnknownWord(11)))
+        ));
+    }
+
+    #[test]
+    fn import_core_descriptor() {
+        use crate::tests::{derive, taproot_input, test_key, unsigned_psbt};
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let tprv = ours.0[0].to_string();
+        // a line as `listdescriptors true` prints it, checksum included
+        let (desc, secrets) =
+            Descriptor::parse_descriptor(&secp, &format!("tr({}/86h/1h/0h/0/*)", tprv)).unwrap();
+        let line = desc.to_string_with_secret(&secrets);
+        assert!(line.contains('#'));
+
+        let imported = SigningKey::from_core_line(&secp, &line).unwrap();
+        let fingerprint = ours.0[0].fingerprint(&secp);
+        assert_eq!(imported.origin, (fingerprint, DerivationPath::master()));
+        assert_eq!(imported.path.to_string(), "m/86'/1'/0'/0");
+        assert_eq!(imported.wildcard, Wildcard::Unhardened);
+        assert_eq!(imported.descriptor, Some(desc));
+        assert!(matches!(
+            imported.key_origin(None),
+            Err(KeyError::DescriptorIndex { ranged: true })
+        ));
+        let (pk, origin) = derive(&secp, &ours, "m/86'/1'/0'/0/5");
+        assert_eq!(imported.key_origin(Some(5)).unwrap(), origin);
+
+        let mut psbt = unsigned_psbt(2);
+        psbt.inputs[0] = taproot_input(&secp, &(pk, origin), &[]);
+        psbt.inputs[1] = taproot_input(&secp, &derive(&secp, &ours, "m/86'/1'/0'/0/6"), &[]);
+        let (signer, opts) = imported.signer_at(&secp, Some(5)).unwrap();
+        let signed = signer
+            .sign_psbt_all_with_options_mut(
+                &mut psbt,
+                &secp,
+                bitcoin::SchnorrSighashType::All,
+                &opts,
+            )
+            .unwrap();
+        assert_eq!(signed, vec![0]);
+        assert!(psbt.inputs[0].tap_key_sig.is_some());
+
+        let dump = format!("# extended private masterkey: {}\n", tprv);
+        let master = SigningKey::from_core_line(&secp, &dump).unwrap();
+        assert_eq!(master.key.0[0].encode(), ours.0[0].encode());
+        assert_eq!(master.wildcard, Wildcard::None);
+        assert!(matches!(
+            master.key_origin(Some(1)),
+            Err(KeyError::DescriptorIndex { ranged: false })
+        ));
+        assert!(matches!(
+            SigningKey::from_core_line(&secp, "tr(notakey)"),
+            Err(KeyError::MalformedCoreLine)
+        ));
+        // the watch only descriptor holds no private key
+        let public = imported.descriptor.unwrap().to_string();
+        assert!(matches!(
+            SigningKey::from_core_line(&secp, &public),
+            Err(KeyError::DescriptorKeyCount(0))
+        ));
+    }
+}
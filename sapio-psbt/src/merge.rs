@@ -0,0 +1,269 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Combining the signatures from copies of a PSBT signed by different
+//! cosigners, and splitting a PSBT so each input's signers only see their
+//! own input.
+use super::*;
+use std::collections::btree_map::Entry;
+
+/// Errors from [`merge_signed`] and [`recombine`]
+#[derive(Debug, Clone)]
+pub enum PSBTMergeError {
+    /// the two PSBTs do not spend the same unsigned transaction
+    DifferentTransaction,
+    /// both PSBTs have a different key path signature for input `index`
+    ConflictingKeySig { index: usize },
+    /// both PSBTs have a different signature by `key` for `leaf`
+    ConflictingScriptSig {
+        index: usize,
+        key: XOnlyPublicKey,
+        leaf: TapLeafHash,
+    },
+    /// both PSBTs have a different ECDSA signature by `key`
+    ConflictingPartialSig {
+        index: usize,
+        key: bitcoin::PublicKey,
+    },
+    /// [`recombine`] was given a different number of splits than the PSBT
+    /// has inputs
+    SplitCount { inputs: usize, splits: usize },
+}
+
+impl Display for PSBTMergeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for PSBTMergeError {}
+
+/// Copy the `tap_key_sig`, `tap_script_sigs`, and `partial_sigs` of `other`
+/// into `base`. Signatures present in both must be identical. On error `base`
+/// is left unchanged.
+pub fn merge_signed(
+    base: &mut PartiallySignedTransaction,
+    other: &PartiallySignedTransaction,
+) -> Result<(), PSBTMergeError> {
+    if base.unsigned_tx != other.unsigned_tx || base.inputs.len() != other.inputs.len() {
+        return Err(PSBTMergeError::DifferentTransaction);
+    }
+    let mut merged = base.inputs.clone();
+    for (index, (into, from)) in merged.iter_mut().zip(other.inputs.iter()).enumerate() {
+        merge_input(index, into, from)?;
+    }
+    base.inputs = merged;
+    Ok(())
+}
+
+/// Copy the signatures of `from` into `into`, which is input `index`
+fn merge_input(
+    index: usize,
+    into: &mut bitcoin::psbt::Input,
+    from: &bitcoin::psbt::Input,
+) -> Result<(), PSBTMergeError> {
+    match (into.tap_key_sig, from.tap_key_sig) {
+        (Some(a), Some(b)) if a != b => return Err(PSBTMergeError::ConflictingKeySig { index }),
+        (None, b) => into.tap_key_sig = b,
+        _ => {}
+    }
+    for (&(key, leaf), sig) in &from.tap_script_sigs {
+        match into.tap_script_sigs.entry((key, leaf)) {
+            Entry::Vacant(e) => {
+                e.insert(*sig);
+            }
+            Entry::Occupied(e) if e.get() != sig => {
+                return Err(PSBTMergeError::ConflictingScriptSig { index, key, leaf })
+            }
+            Entry::Occupied(_) => {}
+        }
+    }
+    for (&key, sig) in &from.partial_sigs {
+        match into.partial_sigs.entry(key) {
+            Entry::Vacant(e) => {
+                e.insert(*sig);
+            }
+            Entry::Occupied(e) if e.get() != sig => {
+                return Err(PSBTMergeError::ConflictingPartialSig { index, key })
+            }
+            Entry::Occupied(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// One PSBT per input, for handing each input to its own signers. Every
+/// split spends the whole unsigned transaction, as the signatures commit to
+/// it, but only split `i` keeps input `i`'s fields. The other inputs keep
+/// only their `witness_utxo` and `non_witness_utxo`, which taproot sighashes
+/// need, so no other input is signed and their scripts and key origins are
+/// not shared. [`recombine`] puts the signatures back.
+pub fn split_by_input(psbt: &PartiallySignedTransaction) -> Vec<PartiallySignedTransaction> {
+    (0..psbt.inputs.len())
+        .map(|i| {
+            let mut split = psbt.clone();
+            for (j, input) in split.inputs.iter_mut().enumerate() {
+                if j != i {
+                    *input = bitcoin::psbt::Input {
+                        witness_utxo: input.witness_utxo.take(),
+                        non_witness_utxo: input.non_witness_utxo.take(),
+                        ..Default::default()
+                    };
+                }
+            }
+            split
+        })
+        .collect()
+}
+
+/// Copy the signatures of input `i` of `splits[i]` into `psbt`, for splits
+/// made by [`split_by_input`] and signed separately. Signatures on any other
+/// input of a split are ignored, and as with [`merge_signed`] ones already
+/// in `psbt` must be identical. On error `psbt` is left unchanged.
+pub fn recombine(
+    psbt: &mut PartiallySignedTransaction,
+    splits: &[PartiallySignedTransaction],
+) -> Result<(), PSBTMergeError> {
+    if splits.len() != psbt.inputs.len() {
+        return Err(PSBTMergeError::SplitCount {
+            inputs: psbt.inputs.len(),
+            splits: splits.len(),
+        });
+    }
+    let mut merged = psbt.inputs.clone();
+    for (index, (into, split)) in merged.iter_mut().zip(splits).enumerate() {
+        if split.unsigned_tx != psbt.unsigned_tx || split.inputs.len() != splits.len() {
+            return Err(PSBTMergeError::DifferentTransaction);
+        }
+        merge_input(index, into, &split.inputs[index])?;
+    }
+    psbt.inputs = merged;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    #[test]
+    fn merge_two_cosigners() {
+        let secp = Secp256k1::new();
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        let (pk1, origin1) = derive(&secp, &test_key(1), "m/1");
+        let (pk2, origin2) = derive(&secp, &test_key(2), "m/1");
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] = taproot_input(
+            &secp,
+            &derive(&secp, &test_key(3), "m/0"),
+            &[(pk1, origin1), (pk2, origin2)],
+        );
+        let mut first = psbt.clone();
+        test_key(1)
+            .sign_psbt_mut(&mut first, &secp, hash_ty)
+            .unwrap();
+        let mut second = psbt.clone();
+        test_key(2)
+            .sign_psbt_mut(&mut second, &secp, hash_ty)
+            .unwrap();
+
+        merge_signed(&mut first, &second).unwrap();
+        let signers: Vec<_> = first.inputs[0]
+            .tap_script_sigs
+            .keys()
+            .map(|(pk, _)| *pk)
+            .collect();
+        assert_eq!(signers.len(), 2);
+        assert!(signers.contains(&pk1) && signers.contains(&pk2));
+        // merging is idempotent
+        let again = first.clone();
+        merge_signed(&mut first, &again).unwrap();
+        assert_eq!(first.inputs, again.inputs);
+
+        // random aux data makes a different, equally valid, signature
+        let mut resigned = psbt;
+        test_key(2)
+            .sign_psbt_all_with_options_mut(
+                &mut resigned,
+                &secp,
+                hash_ty,
+                &SigningOptions {
+                    aux_rand: AuxRand::Fixed([7; 32]),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let before = first.clone();
+        assert!(matches!(
+            merge_signed(&mut first, &resigned),
+            Err(PSBTMergeError::ConflictingScriptSig { index: 0, key, .. }) if key == pk2
+        ));
+        assert_eq!(first.inputs, before.inputs);
+
+        let mut other_tx = unsigned_psbt(2);
+        assert!(matches!(
+            merge_signed(&mut other_tx, &first),
+            Err(PSBTMergeError::DifferentTransaction)
+        ));
+    }
+
+    #[test]
+    fn split_sign_recombine() {
+        let secp = Secp256k1::new();
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        let signers = [test_key(1), test_key(2), test_key(3)];
+        let mut psbt = unsigned_psbt(3);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &signers[0], "m/0"), &[]);
+        let leaf_signer = derive(&secp, &signers[1], "m/1");
+        psbt.inputs[1] = taproot_input(&secp, &derive(&secp, &test_key(4), "m/0"), &[leaf_signer]);
+        psbt.inputs[2] = wpkh_input(&secp, &signers[2], "m/84/0").0;
+
+        let mut splits = split_by_input(&psbt);
+        assert_eq!(splits.len(), 3);
+        for (i, split) in splits.iter_mut().enumerate() {
+            assert_eq!(split.unsigned_tx, psbt.unsigned_tx);
+            assert_eq!(split.inputs[i], psbt.inputs[i]);
+            // every signer tries every split, but only finds its own input
+            for key in &signers {
+                key.sign_psbt_mut(split, &secp, hash_ty).unwrap();
+            }
+            for (j, input) in split.inputs.iter().enumerate() {
+                assert_eq!(input.witness_utxo, psbt.inputs[j].witness_utxo);
+                if i != j {
+                    let utxo_only = bitcoin::psbt::Input {
+                        witness_utxo: input.witness_utxo.clone(),
+                        ..Default::default()
+                    };
+                    assert_eq!(input, &utxo_only);
+                }
+            }
+        }
+        let mut recombined = psbt.clone();
+        recombine(&mut recombined, &splits).unwrap();
+        verify_psbt(&recombined, &secp).unwrap();
+        assert!(recombined.inputs[0].tap_key_sig.is_some());
+        assert_eq!(recombined.inputs[1].tap_script_sigs.len(), 1);
+        assert_eq!(recombined.inputs[2].partial_sigs.len(), 1);
+        // signing deterministically, the splits sign just as the whole would
+        let mut whole = psbt.clone();
+        for key in &signers {
+            key.sign_psbt_mut(&mut whole, &secp, hash_ty).unwrap();
+        }
+        assert_eq!(recombined, whole);
+
+        assert!(matches!(
+            recombine(&mut psbt, &splits[..2]),
+            Err(PSBTMergeError::SplitCount {
+                inputs: 3,
+                splits: 2
+            })
+        ));
+        splits[2].unsigned_tx.lock_time = 1;
+        assert!(matches!(
+            recombine(&mut psbt, &splits),
+            Err(PSBTMergeError::DifferentTransaction)
+        ));
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Signing with several independent [`SigningKey`]s in one pass.
+use super::*;
+
+/// Several [`SigningKey`]s held together, e.g. different accounts or
+/// cosigners on one machine
+pub struct MultiSigningKey(pub Vec<SigningKey>);
+
+/// The signatures added to a single input by a [`MultiSigningKey`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MultiInputSignatures {
+    /// the signatures added across all of the keys
+    pub signatures: InputSignatures,
+    /// how many of the keys added at least one signature
+    pub contributing_keys: usize,
+}
+
+impl MultiSigningKey {
+    /// Sign input `idx` with each key in turn. A signature one key makes is
+    /// only counted once, even if a later key holds the same xpriv, and
+    /// signatures already in the PSBT are not counted at all.
+    pub fn sign_psbt_input_mut<C: Signing + Verification>(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        secp: &Secp256k1<C>,
+        idx: usize,
+        hash_ty: bitcoin::SchnorrSighashType,
+    ) -> Result<MultiInputSignatures, PSBTSigningError> {
+        check_psbt(psbt)?;
+        let tx = psbt.clone().extract_tx();
+        let utxos = witness_utxos(psbt);
+        let mut ctx = TxContext::new(&tx, &utxos, &psbt.xpub);
+        let input = psbt
+            .inputs
+            .get_mut(idx)
+            .ok_or(PSBTSigningError::NoInputAtIndex(idx))?;
+        sign_with_each(&self.0, secp, &mut ctx, idx, input, hash_ty).map(|(signers, signatures)| {
+            MultiInputSignatures {
+                signatures,
+                contributing_keys: signers.len(),
+            }
+        })
+    }
+
+    /// Sign every input with every key, returning the inputs which received
+    /// at least one new signature
+    pub fn sign_psbt_all_mut<C: Signing + Verification>(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        secp: &Secp256k1<C>,
+        hash_ty: bitcoin::SchnorrSighashType,
+    ) -> Result<Vec<(usize, MultiInputSignatures)>, PSBTSigningError> {
+        check_psbt(psbt)?;
+        let tx = psbt.clone().extract_tx();
+        let utxos = witness_utxos(psbt);
+        let mut ctx = TxContext::new(&tx, &utxos, &psbt.xpub);
+        let mut signed = vec![];
+        for (idx, input) in psbt.inputs.iter_mut().enumerate() {
+            let (signers, signatures) =
+                sign_with_each(&self.0, secp, &mut ctx, idx, input, hash_ty)?;
+            if signatures.signed_any() {
+                let contributing_keys = signers.len();
+                signed.push((
+                    idx,
+                    MultiInputSignatures {
+                        signatures,
+                        contributing_keys,
+                    },
+                ));
+            }
+        }
+        Ok(signed)
+    }
+}
+
+/// Which keys signed one input, see [`sign_psbt_attributed`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InputAttribution {
+    pub index: usize,
+    /// the positions in `keys` of every key which added a signature
+    pub signers: Vec<usize>,
+    /// the signatures added across all of the keys
+    pub signatures: InputSignatures,
+}
+
+/// Sign every input with whichever of `keys` can, for operators holding
+/// several unrelated xprivs, reporting which key signed what. Every input
+/// is reported, with no `signers` if none of the keys could sign it.
+pub fn sign_psbt_attributed<C: Signing + Verification>(
+    keys: &[SigningKey],
+    psbt: &mut PartiallySignedTransaction,
+    secp: &Secp256k1<C>,
+    hash_ty: bitcoin::SchnorrSighashType,
+) -> Result<Vec<InputAttribution>, PSBTSigningError> {
+    check_psbt(psbt)?;
+    let tx = psbt.clone().extract_tx();
+    let utxos = witness_utxos(psbt);
+    let mut ctx = TxContext::new(&tx, &utxos, &psbt.xpub);
+    psbt.inputs
+        .iter_mut()
+        .enumerate()
+        .map(|(index, input)| {
+            let (signers, signatures) =
+                sign_with_each(keys, secp, &mut ctx, index, input, hash_ty)?;
+            Ok(InputAttribution {
+                index,
+                signers,
+                signatures,
+            })
+        })
+        .collect()
+}
+
+/// Signs `input`, which is input `idx` of `ctx`'s transaction, with each of
+/// `keys` in turn. Returns the positions in `keys` of every key which added a
+/// signature, and the signatures added across all of them.
+fn sign_with_each<C: Signing + Verification>(
+    keys: &[SigningKey],
+    secp: &Secp256k1<C>,
+    ctx: &mut TxContext<'_>,
+    idx: usize,
+    input: &mut bitcoin::psbt::Input,
+    hash_ty: bitcoin::SchnorrSighashType,
+) -> Result<(Vec<usize>, InputSignatures), PSBTSigningError> {
+    let opts = SigningOptions::default();
+    let mut signers = vec![];
+    let mut total = InputSignatures::default();
+    for (k, key) in keys.iter().enumerate() {
+        let before = SigCounts::of(input);
+        key.sign_input_in_context(secp, ctx, idx, input, hash_ty, &opts)?;
+        let added = SigCounts::of(input).since(&before);
+        if added.signed_any() {
+            signers.push(k);
+        }
+        accumulate(&mut total, &added);
+    }
+    Ok((signers, total))
+}
+
+fn accumulate(total: &mut InputSignatures, added: &InputSignatures) {
+    total.key_path_signed |= added.key_path_signed;
+    total.script_sigs_added += added.script_sigs_added;
+    total.partial_sigs_added += added.partial_sigs_added;
+}
+
+/// How many signatures of each kind an input holds
+struct SigCounts {
+    key_path: bool,
+    script: usize,
+    partial: usize,
+}
+
+impl SigCounts {
+    fn of(input: &bitcoin::psbt::Input) -> Self {
+        SigCounts {
+            key_path: input.tap_key_sig.is_some(),
+            script: input.tap_script_sigs.len(),
+            partial: input.partial_sigs.len(),
+        }
+    }
+    fn since(&self, before: &SigCounts) -> InputSignatures {
+        InputSignatures {
+            key_path_signed: self.key_path && !before.key_path,
+            script_sigs_added: self.script - before.script,
+            partial_sigs_added: self.partial - before.partial,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    #[test]
+    fn attributes_each_input() {
+        let secp = Secp256k1::new();
+        let keys = [test_key(1), test_key(2), test_key(3)];
+        let mut psbt = unsigned_psbt(3);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &keys[1], "m/0"), &[]);
+        psbt.inputs[1] = wpkh_input(&secp, &keys[0], "m/84/0").0;
+        psbt.inputs[2] = taproot_input(&secp, &derive(&secp, &test_key(4), "m/0"), &[]);
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+
+        let attribution = sign_psbt_attributed(&keys, &mut psbt, &secp, hash_ty).unwrap();
+        let signers: Vec<_> = attribution.iter().map(|a| a.signers.clone()).collect();
+        assert_eq!(signers, vec![vec![1], vec![0], vec![]]);
+        assert!(attribution[0].signatures.key_path_signed);
+        assert_eq!(attribution[1].signatures.partial_sigs_added, 1);
+        assert!(!attribution[2].signatures.signed_any());
+
+        // signing again attributes nothing new
+        let again = sign_psbt_attributed(&keys, &mut psbt, &secp, hash_ty).unwrap();
+        assert!(again.iter().all(|a| a.signers.is_empty()));
+    }
+
+    #[test]
+    fn two_keys_different_leaves() {
+        let secp = Secp256k1::new();
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        let mut psbt = unsigned_psbt(2);
+        psbt.inputs[0] = taproot_input(
+            &secp,
+            &derive(&secp, &test_key(3), "m/0"),
+            &[
+                derive(&secp, &test_key(1), "m/1"),
+                derive(&secp, &test_key(2), "m/1"),
+            ],
+        );
+        psbt.inputs[1] = taproot_input(&secp, &derive(&secp, &test_key(3), "m/1"), &[]);
+        // the repeated key must not be counted twice
+        let keys = MultiSigningKey(vec![test_key(1), test_key(2), test_key(1)]);
+        let signed = keys.sign_psbt_all_mut(&mut psbt, &secp, hash_ty).unwrap();
+        assert_eq!(
+            signed,
+            vec![(
+                0,
+                MultiInputSignatures {
+                    signatures: InputSignatures {
+                        script_sigs_added: 2,
+                        ..Default::default()
+                    },
+                    contributing_keys: 2,
+                }
+            )]
+        );
+        assert_eq!(psbt.inputs[0].tap_script_sigs.len(), 2);
+        assert!(psbt.inputs[0].tap_key_sig.is_none());
+    }
+}
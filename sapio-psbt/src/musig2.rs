@@ -0,0 +1,745 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! MuSig2 (BIP-327) signing for taproot key path spends whose internal key is
+//! an aggregate of several participants' keys.
+//!
+//! The ceremony over a PSBT input, with `participants` being every party's
+//! full public key, is:
+//! 1. each party calls [`SigningKey::musig2_add_nonce_mut`], keeping the
+//!    returned [`SecNonce`] to itself
+//! 2. once every public nonce is in the PSBT, each party calls
+//!    [`SigningKey::musig2_partial_sign_mut`] with its [`SecNonce`]
+//! 3. anyone calls [`musig2_aggregate_mut`] to fill in `tap_key_sig`
+//!
+//! Public nonces and partial signatures travel in the input's proprietary
+//! fields under [`crate::SAPIO_PREFIX`], keyed by participant. Secret nonces
+//! never enter the PSBT, and must never be used twice, so [`SecNonce`] is
+//! consumed by signing.
+use super::*;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::schnorr::TweakedPublicKey;
+use bitcoin::secp256k1::schnorr::Signature;
+use bitcoin::secp256k1::{Parity, PublicKey, Scalar, SecretKey};
+use bitcoin::util::sighash::SighashCache;
+use bitcoin::util::taproot::{TapBranchHash, TapTweakHash};
+
+use crate::proprietary::{
+    sapio_key, MUSIG2_PARTIAL_SIG as PARTIAL_SIG_SUBTYPE, MUSIG2_PUBNONCE as PUBNONCE_SUBTYPE,
+};
+
+/// Errors from the MuSig2 ceremony
+#[derive(Debug, Clone)]
+pub enum MuSigError {
+    Signing(PSBTSigningError),
+    NoParticipants,
+    /// the same key appears twice among the participants
+    DuplicateParticipant(PublicKey),
+    /// none of our keys is one of the participants
+    NotAParticipant,
+    /// the participants do not aggregate to the input's `tap_internal_key`
+    AggregateKeyMismatch {
+        expected: Option<XOnlyPublicKey>,
+        aggregate: XOnlyPublicKey,
+    },
+    /// the secret nonce was generated for a different participant key
+    NonceKeyMismatch,
+    MissingNonce(PublicKey),
+    MissingPartialSig(PublicKey),
+    /// the participant's partial signature does not verify against its key
+    /// and nonce
+    InvalidPartialSig(PublicKey),
+    /// a participant's proprietary nonce or partial signature is malformed
+    InvalidProprietaryValue(PublicKey),
+    /// an intermediate value was zero or the point at infinity, which only
+    /// happens with negligible probability unless a participant misbehaves
+    Degenerate,
+}
+
+impl Display for MuSigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for MuSigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MuSigError::Signing(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<PSBTSigningError> for MuSigError {
+    fn from(e: PSBTSigningError) -> Self {
+        MuSigError::Signing(e)
+    }
+}
+
+/// The aggregate of the participants' keys, along with any tweaks applied
+#[derive(Debug, Clone)]
+pub struct KeyAggContext {
+    keys: Vec<PublicKey>,
+    coefficients: Vec<Scalar>,
+    q: PublicKey,
+    /// whether the accumulated sign `gacc` is -1
+    gacc_negated: bool,
+    /// the accumulated tweak `tacc`, `None` when zero
+    tacc: Option<SecretKey>,
+}
+
+impl KeyAggContext {
+    /// `KeyAgg` from BIP-327. The order of `keys` matters, see
+    /// [`KeyAggContext::sorted`].
+    pub fn new<C: Verification>(
+        secp: &Secp256k1<C>,
+        keys: &[PublicKey],
+    ) -> Result<Self, MuSigError> {
+        let first = keys.first().ok_or(MuSigError::NoParticipants)?;
+        let serialized: Vec<u8> = keys.iter().flat_map(|k| k.serialize()).collect();
+        let list = tagged_hash("KeyAgg list", &[&serialized]);
+        let second = keys.iter().find(|k| *k != first);
+        let coefficients = keys
+            .iter()
+            .map(|k| {
+                if Some(k) == second {
+                    Ok(Scalar::ONE)
+                } else {
+                    to_scalar(tagged_hash("KeyAgg coefficient", &[&list, &k.serialize()]))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let points = keys
+            .iter()
+            .zip(&coefficients)
+            .map(|(k, a)| k.mul_tweak(secp, a).map_err(|_| MuSigError::Degenerate))
+            .collect::<Result<Vec<_>, _>>()?;
+        let q = PublicKey::combine_keys(&points.iter().collect::<Vec<_>>())
+            .map_err(|_| MuSigError::Degenerate)?;
+        Ok(KeyAggContext {
+            keys: keys.to_vec(),
+            coefficients,
+            q,
+            gacc_negated: false,
+            tacc: None,
+        })
+    }
+
+    /// [`KeyAggContext::new`] over the keys in BIP-327 `KeySort` order, so
+    /// that participants need not agree on an ordering
+    pub fn sorted<C: Verification>(
+        secp: &Secp256k1<C>,
+        keys: &[PublicKey],
+    ) -> Result<Self, MuSigError> {
+        let mut keys = keys.to_vec();
+        keys.sort_by_key(|k| k.serialize());
+        Self::new(secp, &keys)
+    }
+
+    /// The aggregate key, to be used as the `tap_internal_key` before any
+    /// tweak is applied
+    pub fn agg_pk(&self) -> XOnlyPublicKey {
+        self.q.x_only_public_key().0
+    }
+
+    /// Apply the BIP-341 output key tweak for `merkle_root`
+    pub fn apply_taproot_tweak<C: Verification>(
+        &mut self,
+        secp: &Secp256k1<C>,
+        merkle_root: Option<TapBranchHash>,
+    ) -> Result<(), MuSigError> {
+        let t = TapTweakHash::from_key_and_tweak(self.agg_pk(), merkle_root).into_inner();
+        let t = to_scalar(t)?;
+        let negate = !has_even_y(&self.q);
+        let q = if negate { self.q.negate(secp) } else { self.q };
+        self.q = q
+            .add_exp_tweak(secp, &t)
+            .map_err(|_| MuSigError::Degenerate)?;
+        self.gacc_negated ^= negate;
+        let tacc = match self.tacc {
+            None => SecretKey::from_slice(&t.to_be_bytes()),
+            Some(tacc) if negate => tacc.negate().add_tweak(&t),
+            Some(tacc) => tacc.add_tweak(&t),
+        };
+        self.tacc = Some(tacc.map_err(|_| MuSigError::Degenerate)?);
+        Ok(())
+    }
+
+    fn coefficient(&self, pk: &PublicKey) -> Option<Scalar> {
+        let idx = self.keys.iter().position(|k| k == pk)?;
+        Some(self.coefficients[idx])
+    }
+}
+
+/// A participant's secret nonce. Erased on drop.
+pub struct SecNonce {
+    k1: SecretKey,
+    k2: SecretKey,
+    pk: PublicKey,
+}
+
+impl Drop for SecNonce {
+    fn drop(&mut self) {
+        self.k1.non_secure_erase();
+        self.k2.non_secure_erase();
+    }
+}
+
+/// A participant's public nonce, shared with the others
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PubNonce {
+    r1: PublicKey,
+    r2: PublicKey,
+}
+
+impl PubNonce {
+    pub fn serialize(&self) -> [u8; 66] {
+        let mut out = [0; 66];
+        out[..33].copy_from_slice(&self.r1.serialize());
+        out[33..].copy_from_slice(&self.r2.serialize());
+        out
+    }
+    pub fn from_slice(data: &[u8]) -> Option<Self> {
+        if data.len() != 66 {
+            return None;
+        }
+        Some(PubNonce {
+            r1: PublicKey::from_slice(&data[..33]).ok()?,
+            r2: PublicKey::from_slice(&data[33..]).ok()?,
+        })
+    }
+}
+
+/// A participant's partial signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartialSig(SecretKey);
+
+impl PartialSig {
+    pub fn serialize(&self) -> [u8; 32] {
+        self.0.secret_bytes()
+    }
+    pub fn from_slice(data: &[u8]) -> Option<Self> {
+        SecretKey::from_slice(data).ok().map(PartialSig)
+    }
+}
+
+/// Fresh random nonces for the participant with key `pk`
+#[cfg(feature = "native")]
+pub fn generate_nonce<C: Signing>(secp: &Secp256k1<C>, pk: PublicKey) -> (SecNonce, PubNonce) {
+    let mut rng = rand::thread_rng();
+    let (k1, k2) = (SecretKey::new(&mut rng), SecretKey::new(&mut rng));
+    let public = PubNonce {
+        r1: k1.public_key(secp),
+        r2: k2.public_key(secp),
+    };
+    (SecNonce { k1, k2, pk }, public)
+}
+
+/// `NonceAgg` from BIP-327
+pub fn aggregate_nonces(nonces: &[PubNonce]) -> Result<PubNonce, MuSigError> {
+    if nonces.is_empty() {
+        return Err(MuSigError::NoParticipants);
+    }
+    let sum = |f: fn(&PubNonce) -> &PublicKey| {
+        PublicKey::combine_keys(&nonces.iter().map(f).collect::<Vec<_>>())
+            .map_err(|_| MuSigError::Degenerate)
+    };
+    Ok(PubNonce {
+        r1: sum(|n| &n.r1)?,
+        r2: sum(|n| &n.r2)?,
+    })
+}
+
+/// The values derived from the aggregate nonce and the message
+struct Session {
+    b: Scalar,
+    r: PublicKey,
+    e: Scalar,
+}
+
+impl Session {
+    fn new<C: Verification>(
+        secp: &Secp256k1<C>,
+        ctx: &KeyAggContext,
+        aggnonce: &PubNonce,
+        msg: &[u8; 32],
+    ) -> Result<Self, MuSigError> {
+        let q = ctx.q.x_only_public_key().0.serialize();
+        let b = to_scalar(tagged_hash(
+            "MuSig/noncecoef",
+            &[&aggnonce.serialize(), &q, msg],
+        ))?;
+        let r = aggnonce
+            .r2
+            .mul_tweak(secp, &b)
+            .and_then(|r2| aggnonce.r1.combine(&r2))
+            .map_err(|_| MuSigError::Degenerate)?;
+        let e = to_scalar(tagged_hash(
+            "BIP0340/challenge",
+            &[&r.x_only_public_key().0.serialize(), &q, msg],
+        ))?;
+        Ok(Session { b, r, e })
+    }
+}
+
+/// `Sign` from BIP-327, consuming the secret nonce
+pub fn partial_sign<C: Signing + Verification>(
+    secp: &Secp256k1<C>,
+    ctx: &KeyAggContext,
+    secnonce: SecNonce,
+    sk: &SecretKey,
+    aggnonce: &PubNonce,
+    msg: &[u8; 32],
+) -> Result<PartialSig, MuSigError> {
+    if sk.public_key(secp) != secnonce.pk {
+        return Err(MuSigError::NonceKeyMismatch);
+    }
+    let a = ctx
+        .coefficient(&secnonce.pk)
+        .ok_or(MuSigError::NotAParticipant)?;
+    let session = Session::new(secp, ctx, aggnonce, msg)?;
+    let (k1, k2) = if has_even_y(&session.r) {
+        (secnonce.k1, secnonce.k2)
+    } else {
+        (secnonce.k1.negate(), secnonce.k2.negate())
+    };
+    let d = if has_even_y(&ctx.q) == ctx.gacc_negated {
+        sk.negate()
+    } else {
+        *sk
+    };
+    // s = k1 + b*k2 + e*a*d
+    let s = k2.mul_tweak(&session.b).and_then(|bk2| {
+        let ead = d.mul_tweak(&session.e)?.mul_tweak(&a)?;
+        k1.add_tweak(&Scalar::from(bk2))?
+            .add_tweak(&Scalar::from(ead))
+    });
+    s.map(PartialSig).map_err(|_| MuSigError::Degenerate)
+}
+
+/// `PartialSigVerify` from BIP-327, for the participant with key `pk` and
+/// public nonce `pubnonce`
+pub fn verify_partial_sig<C: Verification>(
+    secp: &Secp256k1<C>,
+    ctx: &KeyAggContext,
+    pk: &PublicKey,
+    pubnonce: &PubNonce,
+    aggnonce: &PubNonce,
+    msg: &[u8; 32],
+    sig: &PartialSig,
+) -> Result<(), MuSigError> {
+    let a = ctx.coefficient(pk).ok_or(MuSigError::NotAParticipant)?;
+    let session = Session::new(secp, ctx, aggnonce, msg)?;
+    let invalid = |_| MuSigError::InvalidPartialSig(*pk);
+    // s*G == Re + e*a*g*P, with the signs partial_sign applies to k and d
+    let re = pubnonce
+        .r2
+        .mul_tweak(secp, &session.b)
+        .and_then(|br2| pubnonce.r1.combine(&br2))
+        .map_err(invalid)?;
+    let re = if has_even_y(&session.r) {
+        re
+    } else {
+        re.negate(secp)
+    };
+    let p = if has_even_y(&ctx.q) == ctx.gacc_negated {
+        pk.negate(secp)
+    } else {
+        *pk
+    };
+    let eap = p
+        .mul_tweak(secp, &session.e)
+        .and_then(|ep| ep.mul_tweak(secp, &a))
+        .map_err(invalid)?;
+    match re.combine(&eap) {
+        Ok(expected) if Ok(expected) == generator().mul_tweak(secp, &Scalar::from(sig.0)) => Ok(()),
+        _ => Err(MuSigError::InvalidPartialSig(*pk)),
+    }
+}
+
+/// `PartialSigAgg` from BIP-327
+pub fn aggregate_partial_sigs<C: Verification>(
+    secp: &Secp256k1<C>,
+    ctx: &KeyAggContext,
+    aggnonce: &PubNonce,
+    msg: &[u8; 32],
+    sigs: &[PartialSig],
+) -> Result<Signature, MuSigError> {
+    let session = Session::new(secp, ctx, aggnonce, msg)?;
+    let (first, rest) = sigs.split_first().ok_or(MuSigError::NoParticipants)?;
+    let mut s = first.0;
+    for sig in rest {
+        s = s
+            .add_tweak(&Scalar::from(sig.0))
+            .map_err(|_| MuSigError::Degenerate)?;
+    }
+    if let Some(tacc) = ctx.tacc {
+        // s += e*g*tacc
+        let g = !has_even_y(&ctx.q);
+        let et = tacc
+            .mul_tweak(&session.e)
+            .map_err(|_| MuSigError::Degenerate)?;
+        let et = if g { et.negate() } else { et };
+        s = s
+            .add_tweak(&Scalar::from(et))
+            .map_err(|_| MuSigError::Degenerate)?;
+    }
+    let mut sig = [0; 64];
+    sig[..32].copy_from_slice(&session.r.x_only_public_key().0.serialize());
+    sig[32..].copy_from_slice(&s.secret_bytes());
+    Signature::from_slice(&sig).map_err(|_| MuSigError::Degenerate)
+}
+
+impl SigningKey {
+    /// Step 1 of the ceremony: add our public nonce for input `idx` to the
+    /// PSBT, returning the secret nonce to pass to
+    /// [`SigningKey::musig2_partial_sign_mut`]
+    #[cfg(feature = "native")]
+    pub fn musig2_add_nonce_mut<C: Signing + Verification>(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        secp: &Secp256k1<C>,
+        idx: usize,
+        participants: &[PublicKey],
+    ) -> Result<SecNonce, MuSigError> {
+        let ctx = input_key_agg(secp, psbt, idx, participants)?;
+        let input = &mut psbt.inputs[idx];
+        let (pk, mut sk) = self.find_participant_key(secp, input, &ctx)?;
+        sk.non_secure_erase();
+        let (secnonce, pubnonce) = generate_nonce(secp, pk);
+        input.proprietary.insert(
+            proprietary_key(PUBNONCE_SUBTYPE, &pk),
+            pubnonce.serialize().to_vec(),
+        );
+        Ok(secnonce)
+    }
+
+    /// Step 2 of the ceremony: once every participant's nonce is in the
+    /// PSBT, add our partial signature for the key path of input `idx`. This
+    /// mirrors signing the top key for a single party.
+    pub fn musig2_partial_sign_mut<C: Signing + Verification>(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        secp: &Secp256k1<C>,
+        idx: usize,
+        participants: &[PublicKey],
+        secnonce: SecNonce,
+        hash_ty: bitcoin::SchnorrSighashType,
+    ) -> Result<(), MuSigError> {
+        let msg = key_spend_msg(psbt, idx, hash_ty)?;
+        let ctx = input_key_agg(secp, psbt, idx, participants)?;
+        let input = &mut psbt.inputs[idx];
+        let aggnonce = aggregate_nonces(&read_nonces(input, &ctx)?)?;
+        let (pk, mut sk) = self.find_participant_key(secp, input, &ctx)?;
+        let sig = partial_sign(secp, &ctx, secnonce, &sk, &aggnonce, &msg);
+        sk.non_secure_erase();
+        input.proprietary.insert(
+            proprietary_key(PARTIAL_SIG_SUBTYPE, &pk),
+            sig?.serialize().to_vec(),
+        );
+        Ok(())
+    }
+
+    /// Our secret key for one of the participants, either a master key or
+    /// one derived via `bip32_derivation`
+    fn find_participant_key<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        input: &bitcoin::psbt::Input,
+        ctx: &KeyAggContext,
+    ) -> Result<(PublicKey, SecretKey), MuSigError> {
+        for key in self.0.iter() {
+            let pk = key.private_key.public_key(secp);
+            if ctx.keys.contains(&pk) {
+                return Ok((pk, key.private_key));
+            }
+        }
+        let fingerprints_map =
+            self.compute_fingerprint_map(secp, &SigningOptions::default(), &BTreeMap::new());
+        for signer in
+            self.compute_matching_ecdsa_keys(secp, &input.bip32_derivation, &fingerprints_map)
+        {
+            let (pk, mut sk) = signer?;
+            if ctx.keys.contains(&pk.inner) {
+                return Ok((pk.inner, sk));
+            }
+            sk.non_secure_erase();
+        }
+        Err(MuSigError::NotAParticipant)
+    }
+}
+
+/// Step 3 of the ceremony: combine every participant's partial signature for
+/// input `idx` into its `tap_key_sig`
+pub fn musig2_aggregate_mut<C: Verification>(
+    psbt: &mut PartiallySignedTransaction,
+    secp: &Secp256k1<C>,
+    idx: usize,
+    participants: &[PublicKey],
+    hash_ty: bitcoin::SchnorrSighashType,
+) -> Result<(), MuSigError> {
+    let msg = key_spend_msg(psbt, idx, hash_ty)?;
+    let ctx = input_key_agg(secp, psbt, idx, participants)?;
+    let input = &mut psbt.inputs[idx];
+    let nonces = read_nonces(input, &ctx)?;
+    let aggnonce = aggregate_nonces(&nonces)?;
+    let sigs = ctx
+        .keys
+        .iter()
+        .zip(&nonces)
+        .map(|(pk, pubnonce)| {
+            let value = input
+                .proprietary
+                .get(&proprietary_key(PARTIAL_SIG_SUBTYPE, pk))
+                .ok_or(MuSigError::MissingPartialSig(*pk))?;
+            let sig =
+                PartialSig::from_slice(value).ok_or(MuSigError::InvalidProprietaryValue(*pk))?;
+            verify_partial_sig(secp, &ctx, pk, pubnonce, &aggnonce, &msg, &sig)?;
+            Ok(sig)
+        })
+        .collect::<Result<Vec<_>, MuSigError>>()?;
+    let sig = aggregate_partial_sigs(secp, &ctx, &aggnonce, &msg, &sigs)?;
+    input.tap_key_sig = Some(SchnorrSig { sig, hash_ty });
+    Ok(())
+}
+
+/// The key aggregation context for input `idx`, checked against its internal
+/// key, tweaked by its merkle root and checked against the output it spends
+fn input_key_agg<C: Verification>(
+    secp: &Secp256k1<C>,
+    psbt: &PartiallySignedTransaction,
+    idx: usize,
+    participants: &[PublicKey],
+) -> Result<KeyAggContext, MuSigError> {
+    let (input, txin) = psbt
+        .inputs
+        .get(idx)
+        .zip(psbt.unsigned_tx.input.get(idx))
+        .ok_or(PSBTSigningError::NoInputAtIndex(idx))?;
+    let mut ctx = KeyAggContext::sorted(secp, participants)?;
+    if let Some(dup) = ctx.keys.windows(2).find(|w| w[0] == w[1]) {
+        return Err(MuSigError::DuplicateParticipant(dup[0]));
+    }
+    if input.tap_internal_key != Some(ctx.agg_pk()) {
+        return Err(MuSigError::AggregateKeyMismatch {
+            expected: input.tap_internal_key,
+            aggregate: ctx.agg_pk(),
+        });
+    }
+    ctx.apply_taproot_tweak(secp, input.tap_merkle_root)?;
+    let utxo = spent_utxo(idx, input, &txin.previous_output)?;
+    let output_key = ctx.q.x_only_public_key().0;
+    let expected =
+        Script::new_v1_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(output_key));
+    if utxo.script_pubkey != expected {
+        return Err(PSBTSigningError::OutputKeyMismatch {
+            index: idx,
+            script_pubkey: utxo.script_pubkey,
+            tweaked: output_key,
+        }
+        .into());
+    }
+    Ok(ctx)
+}
+
+fn read_nonces(
+    input: &bitcoin::psbt::Input,
+    ctx: &KeyAggContext,
+) -> Result<Vec<PubNonce>, MuSigError> {
+    ctx.keys
+        .iter()
+        .map(|pk| {
+            let value = input
+                .proprietary
+                .get(&proprietary_key(PUBNONCE_SUBTYPE, pk))
+                .ok_or(MuSigError::MissingNonce(*pk))?;
+            PubNonce::from_slice(value).ok_or(MuSigError::InvalidProprietaryValue(*pk))
+        })
+        .collect()
+}
+
+fn key_spend_msg(
+    psbt: &PartiallySignedTransaction,
+    idx: usize,
+    hash_ty: bitcoin::SchnorrSighashType,
+) -> Result<[u8; 32], PSBTSigningError> {
+    check_psbt(psbt)?;
+    let utxos = witness_utxos(psbt)?;
+    let tx = psbt.clone().extract_tx();
+    let hash = SighashCache::new(&tx)
+        .taproot_key_spend_signature_hash(idx, &Prevouts::All(&utxos), hash_ty)
+        .map_err(PSBTSigningError::Sighash)?;
+    Ok(hash.into_inner())
+}
+
+fn proprietary_key(subtype: u8, pk: &PublicKey) -> bitcoin::psbt::raw::ProprietaryKey {
+    sapio_key(subtype, pk.serialize().to_vec())
+}
+
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    for d in data {
+        engine.input(d);
+    }
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+fn to_scalar(bytes: [u8; 32]) -> Result<Scalar, MuSigError> {
+    Scalar::from_be_bytes(bytes).map_err(|_| MuSigError::Degenerate)
+}
+
+/// `G`, so that `s*G` needs only a verification context
+fn generator() -> PublicKey {
+    use bitcoin::secp256k1::constants::{GENERATOR_X, GENERATOR_Y};
+    let mut g = [4; 65];
+    g[1..33].copy_from_slice(&GENERATOR_X);
+    g[33..].copy_from_slice(&GENERATOR_Y);
+    PublicKey::from_slice(&g).expect("the generator is on the curve")
+}
+
+fn has_even_y(pk: &PublicKey) -> bool {
+    pk.x_only_public_key().1 == Parity::Even
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "native")]
+    use crate::tests::*;
+    use bitcoin::hashes::hex::FromHex;
+
+    fn pk(hex: &str) -> PublicKey {
+        PublicKey::from_slice(&Vec::from_hex(hex).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn bip327_key_agg_vector() {
+        let secp = Secp256k1::new();
+        let keys = [
+            pk("02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9"),
+            pk("03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659"),
+            pk("023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66"),
+        ];
+        let ctx = KeyAggContext::new(&secp, &keys).unwrap();
+        assert_eq!(
+            ctx.agg_pk().serialize().to_vec(),
+            Vec::from_hex("90539EEDE565F5D054F32CC0C220126889ED1E5D193BAF15AEF344FE59D4610C")
+                .unwrap()
+        );
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn two_party_key_path() {
+        let secp = Secp256k1::new();
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        let (alice, bob) = (test_key(1), test_key(2));
+        let participants = [
+            alice.0[0].private_key.public_key(&secp),
+            bob.0[0].private_key.public_key(&secp),
+        ];
+        let agg = KeyAggContext::sorted(&secp, &participants)
+            .unwrap()
+            .agg_pk();
+        // a script leaf so the output key tweak commits to a merkle root
+        let nobody = (Default::default(), DerivationPath::master());
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] =
+            taproot_input(&secp, &(agg, nobody), &[derive(&secp, &test_key(3), "m/0")]);
+
+        let alice_nonce = alice
+            .musig2_add_nonce_mut(&mut psbt, &secp, 0, &participants)
+            .unwrap();
+        // can't sign until every nonce is present
+        assert!(matches!(
+            alice.musig2_partial_sign_mut(&mut psbt, &secp, 0, &participants, alice_nonce, hash_ty),
+            Err(MuSigError::MissingNonce(pk)) if pk == participants[1]
+        ));
+        let alice_nonce = alice
+            .musig2_add_nonce_mut(&mut psbt, &secp, 0, &participants)
+            .unwrap();
+        let bob_nonce = bob
+            .musig2_add_nonce_mut(&mut psbt, &secp, 0, &participants)
+            .unwrap();
+        assert!(matches!(
+            test_key(3).musig2_add_nonce_mut(&mut psbt.clone(), &secp, 0, &participants),
+            Err(MuSigError::NotAParticipant)
+        ));
+        alice
+            .musig2_partial_sign_mut(&mut psbt, &secp, 0, &participants, alice_nonce, hash_ty)
+            .unwrap();
+        assert!(matches!(
+            musig2_aggregate_mut(&mut psbt.clone(), &secp, 0, &participants, hash_ty),
+            Err(MuSigError::MissingPartialSig(pk)) if pk == participants[1]
+        ));
+        bob.musig2_partial_sign_mut(&mut psbt, &secp, 0, &participants, bob_nonce, hash_ty)
+            .unwrap();
+        // a partial signature which is well formed but wrong is caught, and
+        // blamed on the participant who made it
+        let mut forged = psbt.clone();
+        let alice_sig = forged.inputs[0].proprietary
+            [&proprietary_key(PARTIAL_SIG_SUBTYPE, &participants[0])]
+            .clone();
+        forged.inputs[0].proprietary.insert(
+            proprietary_key(PARTIAL_SIG_SUBTYPE, &participants[1]),
+            alice_sig,
+        );
+        assert!(matches!(
+            musig2_aggregate_mut(&mut forged, &secp, 0, &participants, hash_ty),
+            Err(MuSigError::InvalidPartialSig(pk)) if pk == participants[1]
+        ));
+        assert!(forged.inputs[0].tap_key_sig.is_none());
+        musig2_aggregate_mut(&mut psbt, &secp, 0, &participants, hash_ty).unwrap();
+
+        let input = &psbt.inputs[0];
+        let (output_key, _) = agg.tap_tweak(&secp, input.tap_merkle_root);
+        let msg = key_spend_msg(&psbt, 0, hash_ty).unwrap();
+        let msg = bitcoin::secp256k1::Message::from_digest_slice(&msg).unwrap();
+        secp.verify_schnorr(
+            &input.tap_key_sig.unwrap().sig,
+            &msg,
+            &output_key.to_inner(),
+        )
+        .unwrap();
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn participants_must_match_internal_key() {
+        let secp = Secp256k1::new();
+        let alice = test_key(1);
+        let participants = [
+            alice.0[0].private_key.public_key(&secp),
+            test_key(2).0[0].private_key.public_key(&secp),
+        ];
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &alice, "m/0"), &[]);
+        assert!(matches!(
+            alice.musig2_add_nonce_mut(&mut psbt, &secp, 0, &participants),
+            Err(MuSigError::AggregateKeyMismatch { .. })
+        ));
+        assert!(matches!(
+            alice.musig2_add_nonce_mut(&mut psbt, &secp, 0, &[participants[0]; 2]),
+            Err(MuSigError::DuplicateParticipant(_))
+        ));
+        // the right internal key, but an output committing to something else
+        let agg = KeyAggContext::sorted(&secp, &participants)
+            .unwrap()
+            .agg_pk();
+        let nobody = (Default::default(), DerivationPath::master());
+        psbt.inputs[0] = taproot_input(&secp, &(agg, nobody), &[]);
+        psbt.inputs[0].tap_merkle_root = Some(bitcoin::hashes::Hash::hash(b"not the tree"));
+        assert!(matches!(
+            alice.musig2_add_nonce_mut(&mut psbt, &secp, 0, &participants),
+            Err(MuSigError::Signing(PSBTSigningError::OutputKeyMismatch {
+                index: 0,
+                ..
+            }))
+        ));
+    }
+}
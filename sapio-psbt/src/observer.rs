@@ -0,0 +1,131 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Callbacks fired while signing, so a signing service can emit metrics
+//! through whatever telemetry library it already uses.
+use super::*;
+
+/// Hooks called by [`SigningKey::sign_psbt_all_observed`]. Every method
+/// defaults to doing nothing, and `()` is the observer which observes nothing.
+pub trait SigningObserver {
+    /// input `idx` received the signatures in `sigs`
+    fn on_input_signed(&mut self, _idx: usize, _sigs: &InputSignatures) {}
+    /// none of our keys could sign input `idx`
+    fn on_input_skipped(&mut self, _idx: usize) {}
+    /// signing stopped with `error`, which is also returned to the caller
+    fn on_error(&mut self, _error: &PSBTSigningError) {}
+}
+
+impl SigningObserver for () {}
+
+impl SigningKey {
+    /// Same as [`SigningKey::sign_psbt_all_with_options_mut`], reporting
+    /// each input signed or skipped, and any error, to `observer`
+    pub fn sign_psbt_all_observed<C: Signing + Verification>(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        secp: &Secp256k1<C>,
+        hash_ty: bitcoin::SchnorrSighashType,
+        opts: &SigningOptions,
+        observer: &mut impl SigningObserver,
+    ) -> Result<Vec<usize>, PSBTSigningError> {
+        self.sign_all_observed(psbt, secp, hash_ty, opts, observer)
+            .map_err(|e| {
+                observer.on_error(&e);
+                e
+            })
+    }
+
+    fn sign_all_observed<C: Signing + Verification>(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        secp: &Secp256k1<C>,
+        hash_ty: bitcoin::SchnorrSighashType,
+        opts: &SigningOptions,
+        observer: &mut impl SigningObserver,
+    ) -> Result<Vec<usize>, PSBTSigningError> {
+        if let Some(expected) = opts.expected_network {
+            self.check_network(expected)?;
+        }
+        check_psbt_within(psbt, &opts.limits)?;
+        opts.check_sighash_consistency(psbt.inputs.len(), hash_ty)?;
+        // the unsigned transaction is the same for every input, so one cache
+        // of its midstates serves them all
+        let tx = psbt.clone().extract_tx();
+        let utxos = witness_utxos(psbt);
+        let mut ctx = TxContext::new(&tx, &utxos, &psbt.xpub);
+        let mut signed = vec![];
+        for (idx, input) in psbt.inputs.iter_mut().enumerate() {
+            let hash_ty = opts.sighash_for(idx, hash_ty);
+            let sigs = self.sign_input_in_context(secp, &mut ctx, idx, input, hash_ty, opts)?;
+            if sigs.signed_any() {
+                observer.on_input_signed(idx, &sigs);
+                signed.push(idx);
+            } else {
+                observer.on_input_skipped(idx);
+            }
+        }
+        Ok(signed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    #[derive(Default)]
+    struct Counter {
+        signed: Vec<usize>,
+        script_sigs: usize,
+        skipped: Vec<usize>,
+        errors: usize,
+    }
+    impl SigningObserver for Counter {
+        fn on_input_signed(&mut self, idx: usize, sigs: &InputSignatures) {
+            self.signed.push(idx);
+            self.script_sigs += sigs.script_sigs_added;
+        }
+        fn on_input_skipped(&mut self, idx: usize) {
+            self.skipped.push(idx);
+        }
+        fn on_error(&mut self, _error: &PSBTSigningError) {
+            self.errors += 1;
+        }
+    }
+
+    #[test]
+    fn counts_signed_and_skipped() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let (pk, origin) = derive(&secp, &ours, "m/1");
+        let mut psbt = unsigned_psbt(4);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &test_key(2), "m/0"), &[(pk, origin)]);
+        psbt.inputs[1] = taproot_input(&secp, &derive(&secp, &test_key(2), "m/1"), &[]);
+        psbt.inputs[2] = taproot_input(&secp, &derive(&secp, &ours, "m/2"), &[]);
+        psbt.inputs[3] = wpkh_input(&secp, &test_key(3), "m/84/0").0;
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        let opts = SigningOptions::default();
+
+        let mut counter = Counter::default();
+        let signed = ours
+            .sign_psbt_all_observed(&mut psbt.clone(), &secp, hash_ty, &opts, &mut counter)
+            .unwrap();
+        assert_eq!(signed, vec![0, 2]);
+        assert_eq!(counter.signed, vec![0, 2]);
+        assert_eq!(counter.script_sigs, 1);
+        assert_eq!(counter.skipped, vec![1, 3]);
+        assert_eq!(counter.errors, 0);
+
+        let mut counter = Counter::default();
+        psbt.version = 2;
+        assert!(ours
+            .sign_psbt_all_observed(&mut psbt, &secp, hash_ty, &opts, &mut counter)
+            .is_err());
+        assert!(counter.signed.is_empty() && counter.skipped.is_empty());
+        assert_eq!(counter.errors, 1);
+    }
+}
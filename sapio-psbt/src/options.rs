@@ -0,0 +1,274 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Knobs controlling how a [`crate::SigningKey`] produces signatures.
+#[cfg(feature = "native")]
+use bitcoin::secp256k1::rand::{self, Rng};
+use bitcoin::secp256k1::{Message, Secp256k1, Signing};
+use bitcoin::util::bip32::{Fingerprint, KeySource};
+use bitcoin::util::taproot::TapLeafHash;
+use bitcoin::{KeyPair, Network, SchnorrSighashType, Transaction};
+use std::collections::BTreeMap;
+
+/// Source of the BIP-340 auxiliary randomness mixed into each nonce, for key
+/// path and script path signatures alike. With a deterministic source,
+/// signing the same PSBT again gives byte identical signatures throughout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuxRand {
+    /// No auxiliary randomness, signatures are deterministic
+    None,
+    /// Fresh randomness from the thread rng for every signature
+    #[cfg(feature = "native")]
+    ThreadRng,
+    /// The same caller supplied bytes for every signature, signatures are
+    /// deterministic. The bytes are the `ndata` of libsecp256k1's BIP-340
+    /// nonce function, so this also serves protocols which specify the data
+    /// a nonce is derived from.
+    Fixed([u8; 32]),
+}
+
+impl Default for AuxRand {
+    fn default() -> Self {
+        AuxRand::None
+    }
+}
+
+impl AuxRand {
+    pub(crate) fn sign<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        msg: &Message,
+        kp: &KeyPair,
+    ) -> bitcoin::secp256k1::schnorr::Signature {
+        match self {
+            AuxRand::None => secp.sign_schnorr_no_aux_rand(msg, kp),
+            #[cfg(feature = "native")]
+            AuxRand::ThreadRng => {
+                let aux: [u8; 32] = rand::thread_rng().gen();
+                secp.sign_schnorr_with_aux_rand(msg, kp, &aux)
+            }
+            AuxRand::Fixed(aux) => secp.sign_schnorr_with_aux_rand(msg, kp, aux),
+        }
+    }
+}
+
+/// What to do when an input already has a script signature for the key and
+/// leaf being signed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExistingSignatures {
+    /// leave the existing signature in place without signing again
+    Keep,
+    /// check the existing signature against the sighash we would sign and
+    /// our key, keeping it if it verifies and erroring with
+    /// [`crate::PSBTSigningError::ExistingSignatureMismatch`] if it does not.
+    /// Any [`AuxRand`] works, as no new signature is compared byte for byte.
+    ErrorOnMismatch,
+    /// replace the existing signature
+    Overwrite,
+}
+
+impl Default for ExistingSignatures {
+    fn default() -> Self {
+        ExistingSignatures::Keep
+    }
+}
+
+/// Which spend paths of a taproot input to sign
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaprootPaths {
+    /// the key path and every leaf our keys appear in
+    Both,
+    /// only the key path, for outputs whose scripts we must not sign
+    KeyPathOnly,
+    /// only leaves, for outputs whose internal key is unspendable (such as
+    /// a NUMS point) or whose key path the protocol never uses
+    ScriptPathOnly,
+}
+
+impl Default for TaprootPaths {
+    fn default() -> Self {
+        TaprootPaths::Both
+    }
+}
+
+/// The largest PSBT signing will look at, checked before anything else is,
+/// so that a signer fed PSBTs from the network bounds the work and memory
+/// one can cost it. Larger PSBTs error with
+/// [`crate::PSBTSigningError::PsbtTooLarge`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PsbtLimits {
+    /// the most bytes the PSBT may take serialized
+    pub max_size: usize,
+    /// the most inputs the PSBT may have
+    pub max_inputs: usize,
+}
+
+impl Default for PsbtLimits {
+    /// Well beyond any standard transaction, which can spend about 2500
+    /// inputs at most, even with a `non_witness_utxo` for each
+    fn default() -> Self {
+        PsbtLimits {
+            max_size: 32 * 1024 * 1024,
+            max_inputs: 10_000,
+        }
+    }
+}
+
+/// Options for a signing call. The [`Default`] matches the behavior of the
+/// methods which do not take options.
+#[derive(Debug, Clone, Default)]
+pub struct SigningOptions {
+    /// how to generate the auxiliary randomness for schnorr signatures
+    pub aux_rand: AuxRand,
+    /// error with [`crate::PSBTSigningError::KeyOriginMismatch`] if a taproot
+    /// key origin claims one of our fingerprints but does not derive to the
+    /// expected key, rather than skipping it
+    pub strict_key_origins: bool,
+    /// error with [`crate::PSBTSigningError::InvalidPrevoutAmount`] if a
+    /// `witness_utxo` being signed over is worth zero or more than 21e6 BTC
+    pub validate_prevout_amounts: bool,
+    /// if set, only sign script paths for these leaves, skipping any other
+    /// leaves our keys match. The key path is unaffected.
+    pub tapleaf_allowlist: Option<Vec<TapLeafHash>>,
+    /// annex committed to by taproot signatures, which must start with the
+    /// `0x50` prefix. PSBTs have no field for the annex, so it applies to
+    /// every taproot input signed with these options, and is recorded on
+    /// each with [`crate::proprietary::set_annex`] for the finalizer.
+    pub annex: Option<Vec<u8>>,
+    /// position of the last executed `OP_CODESEPARATOR` for each leaf, leaves
+    /// not listed are signed as if there were none (`0xffffffff`)
+    pub codesep_positions: BTreeMap<TapLeafHash, u32>,
+    /// error with [`crate::PSBTSigningError::NetworkMismatch`] unless every key
+    /// is for this network. Encoded xprivs don't distinguish the test
+    /// networks, so testnet, signet, and regtest all match each other.
+    pub expected_network: Option<Network>,
+    /// how to treat `tap_script_sigs` entries which are already present
+    pub existing_script_sigs: ExistingSignatures,
+    /// where keys which are not masters, e.g. account xprivs, sit in their
+    /// wallet, by the key's own fingerprint. A listed key also signs for key
+    /// origins under its master's fingerprint whose path extends its own.
+    pub account_origins: BTreeMap<Fingerprint, KeySource>,
+    /// sighash types to sign particular inputs with in the batch signers,
+    /// instead of the type passed to the call
+    pub input_sighash_types: BTreeMap<usize, SchnorrSighashType>,
+    /// let the batch signers sign some inputs committing to all outputs
+    /// (`ALL`) and others to none or one (`NONE`, `SINGLE`), rather than
+    /// erroring with [`crate::PSBTSigningError::InconsistentSighash`]
+    pub allow_mixed_sighash: bool,
+    /// error with [`crate::PSBTSigningError::LocktimeTooLow`] if the
+    /// transaction's `nLockTime` is below this, e.g. the height or time an
+    /// `OP_CHECKLOCKTIMEVERIFY` in the script being spent requires
+    pub min_locktime: Option<u32>,
+    /// error with [`crate::PSBTSigningError::SequenceTooLow`] if an input
+    /// being signed has an `nSequence` below its minimum here, e.g. the
+    /// relative lock an `OP_CHECKSEQUENCEVERIFY` requires
+    pub min_sequences: BTreeMap<usize, u32>,
+    /// error with [`crate::PSBTSigningError::NotATaprootInput`] for an input
+    /// with no taproot fields, rather than signing it with ECDSA or finding
+    /// nothing to sign
+    pub expect_taproot: bool,
+    /// compare key origin fingerprints and derived keys in constant time, so
+    /// that a signing oracle's timing does not reveal which of the PSBT's
+    /// keys it holds. Signing is otherwise unchanged.
+    pub constant_time_matching: bool,
+    /// if set, keys derived along key origins are kept here and reused by
+    /// later calls sharing the cache, rather than derived afresh every time
+    pub derivation_cache: Option<std::sync::Arc<crate::DerivationCache>>,
+    /// sign the key path of inputs with no `tap_internal_key` or
+    /// `tap_merkle_root` whose output key is one of our keys untweaked, for
+    /// contracts which hand us the output key directly. Inputs with an
+    /// internal key are tweaked as usual.
+    pub pretweaked_output_keys: bool,
+    /// which spend paths of taproot inputs to sign. Segwit v0 inputs are
+    /// signed either way.
+    pub taproot_paths: TaprootPaths,
+    /// only use a key for key origins under a master fingerprint if the
+    /// PSBT's global `xpub` map lists it, or a key derived from it, under
+    /// that fingerprint. The full public key and chain code are compared, so
+    /// a key which merely shares the 4 byte fingerprint is never derived
+    /// along the origins. Keys the PSBT does not list sign nothing.
+    pub match_global_xpubs: bool,
+    /// error with [`crate::PSBTSigningError::MerkleRootMismatch`] if a
+    /// taproot input's `tap_scripts` do not all prove their leaf is under its
+    /// `tap_merkle_root`, as signatures for such leaves could never be spent
+    pub check_merkle_root: bool,
+    /// the largest PSBT to sign. Methods which do not take options use the
+    /// [`Default`] limits.
+    pub limits: PsbtLimits,
+}
+
+impl SigningOptions {
+    pub(crate) fn sighash_for(
+        &self,
+        idx: usize,
+        hash_ty: SchnorrSighashType,
+    ) -> SchnorrSighashType {
+        self.input_sighash_types
+            .get(&idx)
+            .copied()
+            .unwrap_or(hash_ty)
+    }
+    /// Errors if the batch signers would sign `n_inputs` inputs committing
+    /// to different sets of outputs. `ANYONECANPAY` may be mixed freely.
+    pub(crate) fn check_sighash_consistency(
+        &self,
+        n_inputs: usize,
+        hash_ty: SchnorrSighashType,
+    ) -> Result<(), crate::PSBTSigningError> {
+        if self.allow_mixed_sighash {
+            return Ok(());
+        }
+        // the low bits say which outputs are committed to, `Default` is `ALL`
+        let outputs = |ty: SchnorrSighashType| match ty as u8 & 0x03 {
+            0 => 1,
+            m => m,
+        };
+        let first = self.sighash_for(0, hash_ty);
+        for idx in 1..n_inputs {
+            let ty = self.sighash_for(idx, hash_ty);
+            if outputs(ty) != outputs(first) {
+                return Err(crate::PSBTSigningError::InconsistentSighash {
+                    first,
+                    index: idx,
+                    hash_ty: ty,
+                });
+            }
+        }
+        Ok(())
+    }
+    /// Both checks compare the raw values, so a minimum must use the same
+    /// units (height or time) as the field it checks.
+    pub(crate) fn check_timelocks(
+        &self,
+        tx: &Transaction,
+        idx: usize,
+    ) -> Result<(), crate::PSBTSigningError> {
+        if let Some(minimum) = self.min_locktime {
+            if tx.lock_time < minimum {
+                return Err(crate::PSBTSigningError::LocktimeTooLow {
+                    locktime: tx.lock_time,
+                    minimum,
+                });
+            }
+        }
+        if let (Some(&minimum), Some(txin)) = (self.min_sequences.get(&idx), tx.input.get(idx)) {
+            if txin.sequence < minimum {
+                return Err(crate::PSBTSigningError::SequenceTooLow {
+                    index: idx,
+                    sequence: txin.sequence,
+                    minimum,
+                });
+            }
+        }
+        Ok(())
+    }
+    pub(crate) fn codesep_position(&self, leaf: &TapLeafHash) -> u32 {
+        self.codesep_positions
+            .get(leaf)
+            .copied()
+            .unwrap_or(crate::DEFAULT_CODESEP)
+    }
+}
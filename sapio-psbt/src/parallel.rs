@@ -0,0 +1,101 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Signing the inputs of a PSBT on rayon's thread pool.
+use super::*;
+use rayon::prelude::*;
+
+impl SigningKey {
+    /// Same as [`SigningKey::sign_psbt_all_with_options_mut`], but signs the
+    /// inputs in parallel. Each input is signed into a copy which is written
+    /// back in order, so the result (including which inputs are left signed
+    /// when one fails) is identical to the sequential version.
+    pub fn sign_psbt_all_parallel<C: Signing + Verification>(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        secp: &Secp256k1<C>,
+        hash_ty: bitcoin::SchnorrSighashType,
+        opts: &SigningOptions,
+    ) -> Result<Vec<usize>, PSBTSigningError> {
+        if let Some(expected) = opts.expected_network {
+            self.check_network(expected)?;
+        }
+        check_psbt_within(psbt, &opts.limits)?;
+        opts.check_sighash_consistency(psbt.inputs.len(), hash_ty)?;
+        let tx = psbt.clone().extract_tx();
+        let utxos = witness_utxos(psbt);
+        let results: Vec<_> = psbt
+            .inputs
+            .par_iter()
+            .enumerate()
+            .map_init(
+                || TxContext::new(&tx, &utxos, &psbt.xpub),
+                |ctx, (idx, input)| {
+                    let mut input = input.clone();
+                    let hash_ty = opts.sighash_for(idx, hash_ty);
+                    self.sign_input_in_context(secp, ctx, idx, &mut input, hash_ty, opts)
+                        .map(|summary| (input, summary))
+                },
+            )
+            .collect();
+        let mut signed = vec![];
+        for (idx, result) in results.into_iter().enumerate() {
+            let (input, summary) = result?;
+            psbt.inputs[idx] = input;
+            if summary.signed_any() {
+                signed.push(idx);
+            }
+        }
+        Ok(signed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let mut psbt = unsigned_psbt(24);
+        for (i, input) in psbt.inputs.iter_mut().enumerate() {
+            let leaf = derive(&secp, &ours, &format!("m/1/{}", i));
+            let owner = if i % 3 == 0 { test_key(2) } else { test_key(1) };
+            *input = taproot_input(&secp, &derive(&secp, &owner, "m/0"), &[leaf]);
+        }
+        let opts = SigningOptions::default();
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        let mut sequential = psbt.clone();
+        let expected = ours
+            .sign_psbt_all_with_options_mut(&mut sequential, &secp, hash_ty, &opts)
+            .unwrap();
+        let signed = ours
+            .sign_psbt_all_parallel(&mut psbt, &secp, hash_ty, &opts)
+            .unwrap();
+        assert_eq!(signed, expected);
+        assert_eq!(serialize(&psbt), serialize(&sequential));
+
+        // a failing input leaves the same inputs signed
+        let mut broken = unsigned_psbt(3);
+        for (i, input) in broken.inputs.iter_mut().enumerate() {
+            *input = taproot_input(&secp, &derive(&secp, &ours, &format!("m/{}", i)), &[]);
+        }
+        let mut sequential = broken.clone();
+        let single = bitcoin::SchnorrSighashType::Single;
+        assert!(matches!(
+            ours.sign_psbt_all_with_options_mut(&mut sequential, &secp, single, &opts),
+            Err(PSBTSigningError::SingleWithoutOutput { index: 1 })
+        ));
+        assert!(matches!(
+            ours.sign_psbt_all_parallel(&mut broken, &secp, single, &opts),
+            Err(PSBTSigningError::SingleWithoutOutput { index: 1 })
+        ));
+        assert_eq!(serialize(&broken), serialize(&sequential));
+        assert!(broken.inputs[0].tap_key_sig.is_some());
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Invariants of the signer checked over randomly generated taproot PSBTs.
+//! Failing cases are shrunk and saved by proptest, so they are replayed
+//! first on the next run. Run with
+//! `cargo test -p sapio-psbt --features property-tests`.
+use crate::fixtures::{Leaf, PsbtBuilder};
+use crate::tests::*;
+use crate::*;
+use bitcoin::util::taproot::LeafVersion;
+use proptest::prelude::*;
+
+/// One taproot input to generate: whether its internal key is ours, the
+/// huffman weight of each leaf and whether its signer is ours, and the
+/// amount it spends
+#[derive(Debug, Clone)]
+struct InputSpec {
+    internal_ours: bool,
+    leaves: Vec<(u32, bool)>,
+    amount: u64,
+}
+
+prop_compose! {
+    /// up to 3 leaves, each signed by one of ours or a stranger's key
+    fn input_spec()(
+        internal_ours in any::<bool>(),
+        leaves in prop::collection::vec((1u32..=4, any::<bool>()), 0..=3),
+        amount in 1u64..=100_000_000,
+    ) -> InputSpec {
+        InputSpec { internal_ours, leaves, amount }
+    }
+}
+
+prop_compose! {
+    /// 1 to 6 taproot inputs and the values of 1 to 3 outputs
+    fn psbt_spec()(
+        inputs in prop::collection::vec(input_spec(), 1..=6),
+        outputs in prop::collection::vec(1u64..=1_000_000, 1..=3),
+    ) -> (Vec<InputSpec>, Vec<u64>) {
+        (inputs, outputs)
+    }
+}
+
+fn sighash_type() -> impl Strategy<Value = bitcoin::SchnorrSighashType> {
+    prop::sample::select(vec![
+        bitcoin::SchnorrSighashType::Default,
+        bitcoin::SchnorrSighashType::All,
+        bitcoin::SchnorrSighashType::AllPlusAnyoneCanPay,
+    ])
+}
+
+/// The PSBT `inputs` and `outputs` describe, and which of its inputs have a
+/// key of ours
+fn build_psbt<C: Signing + Verification>(
+    secp: &Secp256k1<C>,
+    ours: &SigningKey,
+    inputs: &[InputSpec],
+    outputs: &[u64],
+) -> (PartiallySignedTransaction, Vec<bool>) {
+    let stranger = test_key(200);
+    let pick =
+        |is_ours: bool, path: String| derive(secp, if is_ours { ours } else { &stranger }, &path);
+    let mut builder = PsbtBuilder::new(secp);
+    for (i, spec) in inputs.iter().enumerate() {
+        let internal = pick(spec.internal_ours, format!("m/{}", i));
+        let leaves: Vec<_> = spec
+            .leaves
+            .iter()
+            .enumerate()
+            .map(|(j, &(weight, is_ours))| {
+                let signer = pick(is_ours, format!("m/{}/{}", i, j));
+                Leaf {
+                    weight,
+                    script: checksig_script(&signer.0),
+                    version: LeafVersion::TapScript,
+                    signers: vec![signer],
+                }
+            })
+            .collect();
+        builder = builder.taproot_input(&internal, &leaves, spec.amount);
+    }
+    for value in outputs {
+        builder = builder.output(*value, Script::new());
+    }
+    let has_ours = inputs
+        .iter()
+        .map(|spec| spec.internal_ours || spec.leaves.iter().any(|(_, is_ours)| *is_ours))
+        .collect();
+    (builder.build(), has_ours)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(256))]
+
+    #[test]
+    fn random_taproot_psbts((inputs, outputs) in psbt_spec(), hash_ty in sighash_type()) {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let (unsigned, has_ours) = build_psbt(&secp, &ours, &inputs, &outputs);
+
+        let mut psbt = unsigned.clone();
+        let signed = ours.sign_psbt_all_mut(&mut psbt, &secp, hash_ty).unwrap();
+        let expected: Vec<_> = (0..has_ours.len()).filter(|i| has_ours[*i]).collect();
+        prop_assert_eq!(signed, expected);
+        // every signature verifies against its recomputed sighash
+        prop_assert!(verify_psbt(&psbt, &secp).is_ok());
+        // inputs without our keys are untouched
+        for (i, mine) in has_ours.iter().enumerate() {
+            if !mine {
+                prop_assert_eq!(&psbt.inputs[i], &unsigned.inputs[i]);
+            }
+        }
+        // signing again changes nothing
+        let mut again = psbt.clone();
+        ours.sign_psbt_all_mut(&mut again, &secp, hash_ty).unwrap();
+        prop_assert_eq!(again, psbt);
+    }
+}
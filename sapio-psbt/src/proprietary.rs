@@ -0,0 +1,183 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Signing metadata kept in an input's BIP-174 proprietary fields, so that
+//! sapio tools can hand one PSBT around rather than the PSBT and a side
+//! channel.
+//!
+//! Every field is keyed by [`SAPIO_PREFIX`] and one of the subtypes below.
+//! Subtypes are never reused once assigned.
+use super::*;
+use bitcoin::hashes::Hash;
+use bitcoin::psbt::raw::ProprietaryKey;
+
+/// The proprietary key prefix of every sapio field
+pub const SAPIO_PREFIX: &[u8] = b"sapio";
+/// a participant's MuSig2 public nonce, keyed by their public key, see
+/// [`crate::musig2`]
+pub const MUSIG2_PUBNONCE: u8 = 0x01;
+/// a participant's MuSig2 partial signature, keyed by their public key
+pub const MUSIG2_PARTIAL_SIG: u8 = 0x02;
+/// the position of the last executed `OP_CODESEPARATOR` signed for a leaf,
+/// keyed by the leaf hash, as a 4 byte little endian integer
+pub const CODESEP_POSITION: u8 = 0x03;
+/// the annex signed for the input, with no key data
+pub const ANNEX: u8 = 0x04;
+
+/// Errors from reading sapio proprietary fields
+#[derive(Debug, Clone)]
+pub enum ProprietaryError {
+    /// the field's key or value is not the length or form its subtype uses
+    Malformed { subtype: u8, key: Vec<u8> },
+}
+
+impl Display for ProprietaryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for ProprietaryError {}
+
+pub(crate) fn sapio_key(subtype: u8, key: Vec<u8>) -> ProprietaryKey {
+    ProprietaryKey {
+        prefix: SAPIO_PREFIX.to_vec(),
+        subtype,
+        key,
+    }
+}
+
+/// The sapio fields of `subtype` in `input`, as key data and value
+fn fields(input: &bitcoin::psbt::Input, subtype: u8) -> impl Iterator<Item = (&[u8], &[u8])> + '_ {
+    input
+        .proprietary
+        .iter()
+        .filter(move |(k, _)| k.prefix == SAPIO_PREFIX && k.subtype == subtype)
+        .map(|(k, v)| (&k.key[..], &v[..]))
+}
+
+/// Record that `leaf` was signed with its last executed `OP_CODESEPARATOR`
+/// at `position`
+pub fn set_codesep_position(input: &mut bitcoin::psbt::Input, leaf: TapLeafHash, position: u32) {
+    input.proprietary.insert(
+        sapio_key(CODESEP_POSITION, leaf.into_inner().to_vec()),
+        position.to_le_bytes().to_vec(),
+    );
+}
+
+/// Every recorded code separator position, in the form
+/// [`SigningOptions::codesep_positions`] takes
+pub fn codesep_positions(
+    input: &bitcoin::psbt::Input,
+) -> Result<BTreeMap<TapLeafHash, u32>, ProprietaryError> {
+    fields(input, CODESEP_POSITION)
+        .map(|(key, value)| {
+            let malformed = || ProprietaryError::Malformed {
+                subtype: CODESEP_POSITION,
+                key: key.to_vec(),
+            };
+            let leaf = TapLeafHash::from_slice(key).map_err(|_| malformed())?;
+            let position = <[u8; 4]>::try_from(value).map_err(|_| malformed())?;
+            Ok((leaf, u32::from_le_bytes(position)))
+        })
+        .collect()
+}
+
+/// Record the annex the input was signed with. PSBTs have no field of their
+/// own for it.
+pub fn set_annex(input: &mut bitcoin::psbt::Input, annex: Vec<u8>) {
+    input.proprietary.insert(sapio_key(ANNEX, vec![]), annex);
+}
+
+/// The recorded annex, in the form [`SigningOptions::annex`] takes
+pub fn annex(input: &bitcoin::psbt::Input) -> Result<Option<Vec<u8>>, ProprietaryError> {
+    let mut annexes = fields(input, ANNEX);
+    match annexes.next() {
+        None => Ok(None),
+        Some(([], value)) => Ok(Some(value.to_vec())),
+        Some((key, _)) => Err(ProprietaryError::Malformed {
+            subtype: ANNEX,
+            key: key.to_vec(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    fn round_trip(psbt: &PartiallySignedTransaction) -> PartiallySignedTransaction {
+        deserialize(&serialize(psbt)).unwrap()
+    }
+
+    #[test]
+    fn codesep_positions_round_trip() {
+        let mut psbt = unsigned_psbt(1);
+        let ver = bitcoin::util::taproot::LeafVersion::TapScript;
+        let a = TapLeafHash::from_script(&Script::new(), ver);
+        let b = TapLeafHash::from_script(&Script::from(vec![0x51]), ver);
+        set_codesep_position(&mut psbt.inputs[0], a, 3);
+        set_codesep_position(&mut psbt.inputs[0], b, u32::MAX - 1);
+        // other applications' fields under the same subtype are left alone
+        psbt.inputs[0].proprietary.insert(
+            ProprietaryKey {
+                prefix: b"other".to_vec(),
+                subtype: CODESEP_POSITION,
+                key: vec![],
+            },
+            vec![],
+        );
+
+        let read = round_trip(&psbt);
+        assert_eq!(
+            codesep_positions(&read.inputs[0]).unwrap(),
+            [(a, 3), (b, u32::MAX - 1)].into_iter().collect()
+        );
+        assert_eq!(annex(&read.inputs[0]).unwrap(), None);
+
+        psbt.inputs[0]
+            .proprietary
+            .insert(sapio_key(CODESEP_POSITION, vec![0; 32]), vec![0; 3]);
+        assert!(matches!(
+            codesep_positions(&psbt.inputs[0]),
+            Err(ProprietaryError::Malformed {
+                subtype: CODESEP_POSITION,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn annex_round_trip() {
+        let mut psbt = unsigned_psbt(2);
+        set_annex(&mut psbt.inputs[1], vec![0x50, 1, 2, 3]);
+        let read = round_trip(&psbt);
+        assert_eq!(annex(&read.inputs[0]).unwrap(), None);
+        assert_eq!(annex(&read.inputs[1]).unwrap(), Some(vec![0x50, 1, 2, 3]));
+        assert!(codesep_positions(&read.inputs[1]).unwrap().is_empty());
+
+        // the recorded annex is the one signed for
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &ours, "m/0"), &[]);
+        set_annex(&mut psbt.inputs[0], vec![0x50, 7]);
+        let opts = SigningOptions {
+            annex: annex(&psbt.inputs[0]).unwrap(),
+            ..Default::default()
+        };
+        let mut signed = round_trip(&psbt);
+        ours.sign_psbt_all_with_options_mut(
+            &mut signed,
+            &secp,
+            bitcoin::SchnorrSighashType::All,
+            &opts,
+        )
+        .unwrap();
+        assert_eq!(annex(&signed.inputs[0]).unwrap(), Some(vec![0x50, 7]));
+        assert!(signed.inputs[0].tap_key_sig.is_some());
+    }
+}
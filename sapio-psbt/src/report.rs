@@ -0,0 +1,296 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Machine readable summaries of a signing pass.
+use super::*;
+use serde::{Deserialize, Serialize};
+
+/// What happened to every input during [`SigningKey::sign_psbt_report`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct SigningReport {
+    pub inputs: Vec<InputReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InputReport {
+    pub index: usize,
+    pub key_path_signed: bool,
+    /// the leaves which received a new script signature, once per signature
+    pub script_sig_leaves: Vec<TapLeafHash>,
+    pub partial_sigs_added: usize,
+    /// the tweaked key of the output spent, if it was key path signed
+    pub output_key: Option<XOnlyPublicKey>,
+    /// whether the key path is unspendable, see [`crate::is_nums_key`]
+    pub nums_internal_key: bool,
+    /// why no signature was added, if none was
+    pub skipped: Option<SkipReason>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "reason", content = "detail", rename_all = "snake_case")]
+pub enum SkipReason {
+    /// none of our keys appear in the input's key origins
+    NoMatchingKey,
+    /// the internal key is unspendable and none of our keys appear in its
+    /// leaves
+    NumsInternalKey,
+    /// signing the input failed
+    Error(String),
+}
+
+impl SigningKey {
+    /// Sign every input, carrying on past inputs which fail, and report what
+    /// was done to each. The PSBT is checked once, and a problem with it as a
+    /// whole is reported against every input.
+    pub fn sign_psbt_report<C: Signing + Verification>(
+        &self,
+        mut psbt: PartiallySignedTransaction,
+        secp: &Secp256k1<C>,
+        hash_ty: bitcoin::SchnorrSighashType,
+    ) -> (PartiallySignedTransaction, SigningReport) {
+        let mut report = SigningReport::default();
+        let checked = check_psbt(&psbt);
+        let tx = psbt.clone().extract_tx();
+        let utxos = witness_utxos(&psbt);
+        let mut ctx = TxContext::new(&tx, &utxos, &psbt.xpub);
+        let opts = SigningOptions::default();
+        for (index, input) in psbt.inputs.iter_mut().enumerate() {
+            let nums_internal_key = input.tap_internal_key.as_ref().map_or(false, is_nums_key);
+            let before = input.tap_script_sigs.clone();
+            let result = match &checked {
+                Ok(()) => self.sign_input_in_context(secp, &mut ctx, index, input, hash_ty, &opts),
+                Err(e) => Err(e.clone()),
+            };
+            let script_sig_leaves = input
+                .tap_script_sigs
+                .keys()
+                .filter(|k| !before.contains_key(k))
+                .map(|(_, leaf)| *leaf)
+                .collect();
+            report.inputs.push(match result {
+                Ok(summary) => InputReport {
+                    index,
+                    key_path_signed: summary.key_path_signed,
+                    script_sig_leaves,
+                    partial_sigs_added: summary.partial_sigs_added,
+                    output_key: summary
+                        .key_path_signed
+                        .then(|| p2tr_output_key(input))
+                        .flatten(),
+                    nums_internal_key,
+                    skipped: (!summary.signed_any()).then(|| {
+                        if nums_internal_key {
+                            SkipReason::NumsInternalKey
+                        } else {
+                            SkipReason::NoMatchingKey
+                        }
+                    }),
+                },
+                Err(e) => InputReport {
+                    index,
+                    key_path_signed: false,
+                    script_sig_leaves,
+                    partial_sigs_added: 0,
+                    output_key: None,
+                    nums_internal_key,
+                    skipped: Some(SkipReason::Error(e.to_string())),
+                },
+            });
+        }
+        (psbt, report)
+    }
+
+    /// Sign every input, carrying on past inputs which fail, and return the
+    /// PSBT with whatever could be signed alongside the error for each input
+    /// which failed. A problem with the PSBT as a whole, such as an
+    /// unsupported version, is reported against every input.
+    pub fn sign_psbt_best_effort<C: Signing + Verification>(
+        &self,
+        mut psbt: PartiallySignedTransaction,
+        secp: &Secp256k1<C>,
+        hash_ty: bitcoin::SchnorrSighashType,
+    ) -> (PartiallySignedTransaction, Vec<(usize, PSBTSigningError)>) {
+        if let Err(e) = check_psbt(&psbt) {
+            let errors = (0..psbt.inputs.len()).map(|i| (i, e.clone())).collect();
+            return (psbt, errors);
+        }
+        let tx = psbt.clone().extract_tx();
+        let utxos = witness_utxos(&psbt);
+        let mut ctx = TxContext::new(&tx, &utxos, &psbt.xpub);
+        let opts = SigningOptions::default();
+        let errors = psbt
+            .inputs
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(idx, input)| {
+                self.sign_input_in_context(secp, &mut ctx, idx, input, hash_ty, &opts)
+                    .err()
+                    .map(|e| (idx, e))
+            })
+            .collect();
+        (psbt, errors)
+    }
+}
+
+/// Signing the key path checked that the witness_utxo pays to the tweaked
+/// key, so it can be read back from there
+fn p2tr_output_key(input: &bitcoin::psbt::Input) -> Option<XOnlyPublicKey> {
+    let script = &input.witness_utxo.as_ref()?.script_pubkey;
+    if !script.is_v1_p2tr() {
+        return None;
+    }
+    XOnlyPublicKey::from_slice(&script[2..]).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+    use bitcoin::util::taproot::LeafVersion;
+
+    #[test]
+    fn best_effort_skips_failed_inputs() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let mut psbt = unsigned_psbt(3);
+        for (i, input) in psbt.inputs.iter_mut().enumerate() {
+            *input = wpkh_input(&secp, &ours, &format!("m/84/{}", i)).0;
+        }
+        psbt.inputs[1].witness_utxo = None;
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+
+        let (signed, errors) = ours.sign_psbt_best_effort(psbt.clone(), &secp, hash_ty);
+        assert!(matches!(
+            errors.as_slice(),
+            [(1, PSBTSigningError::NoUTXOAtIndex(1))]
+        ));
+        assert_eq!(signed.inputs[0].partial_sigs.len(), 1);
+        assert!(signed.inputs[1].partial_sigs.is_empty());
+        assert_eq!(signed.inputs[2].partial_sigs.len(), 1);
+        assert!(ours.sign_psbt_all_mut(&mut psbt, &secp, hash_ty).is_err());
+
+        psbt.version = 2;
+        let (unchanged, errors) = ours.sign_psbt_best_effort(psbt.clone(), &secp, hash_ty);
+        assert_eq!(unchanged, psbt);
+        assert_eq!(errors.len(), 3);
+    }
+
+    #[test]
+    fn nums_key_path_skipped() {
+        use crate::fixtures::Leaf;
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let nums = XOnlyPublicKey::from_slice(&[
+            0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9,
+            0x7a, 0x5e, 0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a,
+            0xce, 0x80, 0x3a, 0xc0,
+        ])
+        .unwrap();
+        assert!(is_nums_key(&nums));
+        // an origin claiming one of our keys must not tempt the signer
+        let internal = (nums, derive(&secp, &ours, "m/0").1);
+        let leaf = |signer: (XOnlyPublicKey, KeySource)| Leaf {
+            weight: 1,
+            script: checksig_script(&signer.0),
+            version: LeafVersion::TapScript,
+            signers: vec![signer],
+        };
+        let psbt = crate::fixtures::PsbtBuilder::new(&secp)
+            .taproot_input(&internal, &[leaf(derive(&secp, &ours, "m/1"))], 10_000)
+            .taproot_input(
+                &internal,
+                &[leaf(derive(&secp, &test_key(2), "m/1"))],
+                10_000,
+            )
+            .taproot_input(&derive(&secp, &test_key(2), "m/0"), &[], 10_000)
+            .build();
+        assert!(is_nums_internal_key(&psbt, 0));
+        // an ordinary internal key, and an index with no input at all
+        assert!(!is_nums_internal_key(&psbt, 2));
+        assert!(!is_nums_internal_key(&psbt, 3));
+        assert!(describe_psbt(&psbt).inputs[1].nums_internal_key);
+
+        let (signed, report) = ours.sign_psbt_report(psbt, &secp, bitcoin::SchnorrSighashType::All);
+        assert!(signed.inputs.iter().all(|i| i.tap_key_sig.is_none()));
+        assert_eq!(signed.inputs[0].tap_script_sigs.len(), 1);
+        assert!(report.inputs[..2].iter().all(|i| i.nums_internal_key));
+        assert!(!report.inputs[2].nums_internal_key);
+        assert_eq!(report.inputs[2].skipped, Some(SkipReason::NoMatchingKey));
+        assert!(!report.inputs[0].key_path_signed);
+        assert_eq!(report.inputs[0].skipped, None);
+        assert_eq!(report.inputs[1].skipped, Some(SkipReason::NumsInternalKey));
+    }
+
+    #[test]
+    fn report_as_json() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let (pk, origin) = derive(&secp, &ours, "m/1");
+        let mut psbt = unsigned_psbt(3);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &ours, "m/0"), &[(pk, origin)]);
+        psbt.inputs[1] = taproot_input(&secp, &derive(&secp, &test_key(2), "m/0"), &[]);
+        psbt.inputs[2] = taproot_input(&secp, &derive(&secp, &ours, "m/2"), &[]);
+        psbt.inputs[2].witness_utxo = None;
+        let leaf = TapLeafHash::from_script(
+            &checksig_script(&pk),
+            bitcoin::util::taproot::LeafVersion::TapScript,
+        );
+
+        // input 2's missing utxo stops every taproot input from signing
+        let (_, report) =
+            ours.sign_psbt_report(psbt.clone(), &secp, bitcoin::SchnorrSighashType::All);
+        assert!(report
+            .inputs
+            .iter()
+            .all(|i| matches!(i.skipped, Some(SkipReason::Error(_)))));
+
+        psbt.inputs[2] = taproot_input(&secp, &derive(&secp, &ours, "m/2"), &[]);
+        let output_key = |i: usize| {
+            let script = &psbt.inputs[i].witness_utxo.as_ref().unwrap().script_pubkey;
+            XOnlyPublicKey::from_slice(&script[2..])
+                .unwrap()
+                .to_string()
+        };
+        let (output_0, output_2) = (output_key(0), output_key(2));
+        let (signed, report) = ours.sign_psbt_report(psbt, &secp, bitcoin::SchnorrSighashType::All);
+        assert!(signed.inputs[0].tap_key_sig.is_some());
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "inputs": [
+                    {
+                        "index": 0,
+                        "key_path_signed": true,
+                        "script_sig_leaves": [leaf.to_string()],
+                        "partial_sigs_added": 0,
+                        "output_key": output_0,
+                        "nums_internal_key": false,
+                        "skipped": null
+                    },
+                    {
+                        "index": 1,
+                        "key_path_signed": false,
+                        "script_sig_leaves": [],
+                        "partial_sigs_added": 0,
+                        "output_key": null,
+                        "nums_internal_key": false,
+                        "skipped": { "reason": "no_matching_key" }
+                    },
+                    {
+                        "index": 2,
+                        "key_path_signed": true,
+                        "script_sig_leaves": [],
+                        "partial_sigs_added": 0,
+                        "output_key": output_2,
+                        "nums_internal_key": false,
+                        "skipped": null
+                    }
+                ]
+            })
+        );
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Working out what a key could sign without signing, from the xpriv
+//! behind an xpub, or from the key itself.
+use super::*;
+
+/// What the xpriv behind an xpub would sign in one input
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Signability {
+    pub index: usize,
+    /// the `tap_internal_key` is ours
+    pub key_path: bool,
+    /// the leaves a script signature would be made for, once per key
+    pub leaves: Vec<TapLeafHash>,
+    /// the `bip32_derivation` keys a segwit v0 signature would be made for
+    pub ecdsa_keys: Vec<bitcoin::PublicKey>,
+    /// origins naming our fingerprint along a hardened path, which only the
+    /// xpriv can check
+    pub hardened_origins: usize,
+}
+
+impl Signability {
+    pub fn signable(&self) -> bool {
+        self.key_path || !self.leaves.is_empty() || !self.ecdsa_keys.is_empty()
+    }
+}
+
+/// Match every input's key origins against `xpub`, mirroring the key
+/// selection of [`SigningKey::sign_psbt_all_mut`]. Sighash and prevout
+/// problems which would make signing fail are not detected.
+pub fn signable_inputs<C: Verification>(
+    secp: &Secp256k1<C>,
+    xpub: &ExtendedPubKey,
+    psbt: &PartiallySignedTransaction,
+) -> Vec<Signability> {
+    let fingerprint = xpub.fingerprint();
+    psbt.inputs
+        .iter()
+        .enumerate()
+        .map(|(index, input)| {
+            let mut report = Signability {
+                index,
+                ..Default::default()
+            };
+            let mut derive = |(f, path): &KeySource| {
+                if *f != fingerprint {
+                    return None;
+                }
+                let derived = xpub.derive_pub(secp, path).ok();
+                if derived.is_none() {
+                    report.hardened_origins += 1;
+                }
+                derived
+            };
+            if !ecdsa::is_taproot_input(input) {
+                let utxo = psbt
+                    .unsigned_tx
+                    .input
+                    .get(index)
+                    .and_then(|txin| spent_utxo(index, input, &txin.previous_output).ok());
+                let spendable = utxo
+                    .as_ref()
+                    .and_then(|u| ecdsa::witness_program(input, &u.script_pubkey))
+                    .map(|p| p.is_v0_p2wpkh() || input.witness_script.is_some())
+                    .unwrap_or(false);
+                let ecdsa_keys = input
+                    .bip32_derivation
+                    .iter()
+                    .filter(|(pk, origin)| derive(origin).map(|d| d.public_key) == Some(**pk))
+                    .map(|(pk, _)| bitcoin::PublicKey::new(*pk));
+                report.ecdsa_keys = ecdsa_keys.filter(|_| spendable).collect();
+                return report;
+            }
+            let ours = |derived: Option<ExtendedPubKey>, x: &XOnlyPublicKey| {
+                derived.map(|d| d.to_x_only_pub()) == Some(*x)
+            };
+            for (x, (leaves, origin)) in &input.tap_key_origins {
+                if ours(derive(origin), x) {
+                    report.leaves.extend(leaves);
+                    if input.tap_internal_key == Some(*x) {
+                        report.key_path = true;
+                    }
+                }
+            }
+            if input.tap_internal_key == Some(xpub.to_x_only_pub()) {
+                report.key_path = true;
+            }
+            report
+        })
+        .collect()
+}
+
+impl SigningKey {
+    /// The leaves of input `idx` one of our keys is listed for in its
+    /// `tap_key_origins`, and so could sign, each once in the order first
+    /// listed. Keys are derived along the origins as signing would, but
+    /// nothing is signed, so a wallet can offer the spend paths to choose
+    /// from. Empty if there is no input at `idx`, and for origins which fail
+    /// to derive.
+    pub fn satisfiable_leaves<C: Signing>(
+        &self,
+        psbt: &PartiallySignedTransaction,
+        idx: usize,
+        secp: &Secp256k1<C>,
+    ) -> Vec<TapLeafHash> {
+        let input = match psbt.inputs.get(idx) {
+            Some(input) => input,
+            None => return vec![],
+        };
+        let opts = SigningOptions::default();
+        let fingerprints_map = self.compute_fingerprint_map(secp, &opts, &psbt.xpub);
+        let mut found = vec![];
+        let matches = self.compute_matching_keys(secp, &input.tap_key_origins, &fingerprints_map);
+        for (mut kp, leaves) in matches.flatten() {
+            kp.non_secure_erase();
+            for leaf in leaves {
+                if !found.contains(leaf) {
+                    found.push(*leaf);
+                }
+            }
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    #[test]
+    fn xpub_matches_xpriv() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let (pk, origin) = derive(&secp, &ours, "m/1");
+        let mut psbt = unsigned_psbt(4);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &ours, "m/0"), &[(pk, origin)]);
+        psbt.inputs[1] = taproot_input(&secp, &derive(&secp, &test_key(2), "m/0"), &[]);
+        psbt.inputs[2] = wpkh_input(&secp, &ours, "m/84/0").0;
+        psbt.inputs[3] = taproot_input(&secp, &derive(&secp, &ours, "m/86'/0"), &[]);
+
+        let xpub = ours.pubkey(&secp)[0];
+        let report = signable_inputs(&secp, &xpub, &psbt);
+        let signable: Vec<_> = report
+            .iter()
+            .filter(|r| r.signable())
+            .map(|r| r.index)
+            .collect();
+        assert_eq!(signable, vec![0, 2]);
+        assert_eq!(report[3].hardened_origins, 1);
+
+        ours.sign_psbt_mut(&mut psbt, &secp, bitcoin::SchnorrSighashType::All)
+            .unwrap();
+        for (r, input) in report.iter().zip(&psbt.inputs) {
+            if r.index == 3 {
+                // only the xpriv could tell this one was ours
+                assert!(input.tap_key_sig.is_some());
+                continue;
+            }
+            assert_eq!(r.key_path, input.tap_key_sig.is_some());
+            let leaves: Vec<_> = input.tap_script_sigs.keys().map(|(_, l)| *l).collect();
+            assert_eq!(r.leaves, leaves);
+            let ecdsa: Vec<_> = input.partial_sigs.keys().cloned().collect();
+            assert_eq!(r.ecdsa_keys, ecdsa);
+        }
+    }
+
+    #[test]
+    fn leaves_for_our_key() {
+        use bitcoin::util::taproot::LeafVersion;
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let mine = derive(&secp, &ours, "m/1");
+        let theirs = derive(&secp, &test_key(2), "m/1");
+        let mut psbt = unsigned_psbt(2);
+        psbt.inputs[0] = taproot_input(
+            &secp,
+            &derive(&secp, &test_key(3), "m/0"),
+            &[theirs.clone(), mine.clone()],
+        );
+        psbt.inputs[1] = taproot_input(&secp, &derive(&secp, &ours, "m/0"), &[theirs]);
+        let unsigned = psbt.clone();
+
+        let leaf = TapLeafHash::from_script(&checksig_script(&mine.0), LeafVersion::TapScript);
+        assert_eq!(ours.satisfiable_leaves(&psbt, 0, &secp), vec![leaf]);
+        // the key path is not a leaf
+        assert!(ours.satisfiable_leaves(&psbt, 1, &secp).is_empty());
+        assert!(ours.satisfiable_leaves(&psbt, 2, &secp).is_empty());
+        assert_eq!(psbt, unsigned);
+
+        ours.sign_psbt_mut(&mut psbt, &secp, bitcoin::SchnorrSighashType::All)
+            .unwrap();
+        let signed: Vec<_> = psbt.inputs[0]
+            .tap_script_sigs
+            .keys()
+            .map(|(_, l)| *l)
+            .collect();
+        assert_eq!(signed, vec![leaf]);
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Signing PSBTs as they arrive on a channel, for long running signers.
+use super::*;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+impl SigningKey {
+    /// Signs every PSBT received on `psbts`, in order, forwarding successes
+    /// to `signed` and failures (with the PSBT as it was left) to `errors`.
+    ///
+    /// Returns once `psbts` is closed and drained, or early if either output
+    /// channel is closed.
+    ///
+    /// Each PSBT is signed without awaiting, so dropping the future never
+    /// forwards a part signed PSBT, but one which was received and not yet
+    /// forwarded is lost.
+    pub async fn sign_stream(
+        &self,
+        mut psbts: Receiver<PartiallySignedTransaction>,
+        signed: Sender<PartiallySignedTransaction>,
+        errors: Sender<(PartiallySignedTransaction, PSBTSigningError)>,
+        hash_ty: bitcoin::SchnorrSighashType,
+    ) {
+        let secp = crate::global_secp();
+        while let Some(psbt) = psbts.recv().await {
+            let sent = match self.sign_psbt(psbt, secp, hash_ty) {
+                Ok(psbt) => signed.send(psbt).await.is_ok(),
+                Err(e) => errors.send(e).await.is_ok(),
+            };
+            if !sent {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+    use tokio::sync::mpsc::channel;
+
+    #[tokio::test]
+    async fn sign_three_in_order() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let (psbt_tx, psbt_rx) = channel(1);
+        let (signed_tx, mut signed_rx) = channel(1);
+        let (error_tx, mut error_rx) = channel(1);
+        let keys: Vec<_> = ["m/0", "m/1", "m/2"]
+            .iter()
+            .map(|path| derive(&secp, &ours, path))
+            .collect();
+        let psbts: Vec<_> = keys
+            .iter()
+            .map(|key| {
+                let mut psbt = unsigned_psbt(1);
+                psbt.inputs[0] = taproot_input(&secp, key, &[]);
+                psbt
+            })
+            .collect();
+        let feed = tokio::spawn(async move {
+            for psbt in psbts {
+                psbt_tx.send(psbt).await.unwrap();
+            }
+        });
+        let signer = tokio::spawn(async move {
+            ours.sign_stream(
+                psbt_rx,
+                signed_tx,
+                error_tx,
+                bitcoin::SchnorrSighashType::All,
+            )
+            .await
+        });
+        for (key, _) in &keys {
+            let psbt = signed_rx.recv().await.unwrap();
+            assert_eq!(psbt.inputs[0].tap_internal_key, Some(*key));
+            assert!(psbt.inputs[0].tap_key_sig.is_some());
+        }
+        feed.await.unwrap();
+        // the feed dropping its sender shuts the signer down
+        signer.await.unwrap();
+        assert!(signed_rx.recv().await.is_none());
+        assert!(error_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn errors_go_to_error_channel() {
+        let (psbt_tx, psbt_rx) = channel(1);
+        let (signed_tx, mut signed_rx) = channel(1);
+        let (error_tx, mut error_rx) = channel(1);
+        let mut psbt = unsigned_psbt(1);
+        // taproot input with no utxo
+        psbt.inputs[0].tap_internal_key = Some(derive(&Secp256k1::new(), &test_key(1), "m/0").0);
+        psbt_tx.send(psbt).await.unwrap();
+        drop(psbt_tx);
+        test_key(1)
+            .sign_stream(
+                psbt_rx,
+                signed_tx,
+                error_tx,
+                bitcoin::SchnorrSighashType::All,
+            )
+            .await;
+        assert!(matches!(
+            error_rx.recv().await,
+            Some((_, PSBTSigningError::NoUTXOAtIndex(0)))
+        ));
+        assert!(signed_rx.recv().await.is_none());
+    }
+}
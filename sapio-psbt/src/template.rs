@@ -0,0 +1,221 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Turning the templates of a compiled Sapio contract into PSBTs a
+//! [`SigningKey`] can sign without further assembly.
+use super::*;
+use bitcoin::util::taproot::{TaprootBuilder, TaprootBuilderError, TaprootSpendInfo};
+use bitcoin::OutPoint;
+use miniscript::Descriptor;
+use sapio::template::Template;
+
+/// The output spent by one input of a [`Template`]
+#[derive(Debug, Clone)]
+pub struct TemplatePrevout {
+    /// the output's outpoint, which the template leaves unset for its first
+    /// input
+    pub outpoint: OutPoint,
+    pub utxo: TxOut,
+    /// the descriptor paying to `utxo`, e.g. the `known_descriptor` of the
+    /// compiled contract the template spends. Without one only the
+    /// `witness_utxo` is filled in.
+    pub descriptor: Option<Descriptor<XOnlyPublicKey>>,
+}
+
+/// Errors from [`psbt_from_template`]
+#[derive(Debug, Clone)]
+pub enum TemplateError {
+    /// there must be one prevout per input of the template's transaction
+    WrongPrevoutCount { expected: usize, found: usize },
+    /// the descriptor of input `index` is not a `tr()` descriptor
+    NotTaproot { index: usize },
+    /// the descriptor of input `index` does not pay to its `utxo`
+    DescriptorMismatch { index: usize },
+    /// the descriptor's script tree could not be built
+    Taproot(TaprootBuilderError),
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for TemplateError {}
+impl From<TaprootBuilderError> for TemplateError {
+    fn from(e: TaprootBuilderError) -> Self {
+        TemplateError::Taproot(e)
+    }
+}
+
+/// A PSBT for `template`'s transaction spending `prevouts`, with the
+/// `witness_utxo`, `tap_internal_key`, `tap_merkle_root`, and `tap_scripts`
+/// of every input with a taproot descriptor. Each key in `origins` is
+/// recorded in `tap_key_origins` of the inputs where it is the internal key
+/// or is pushed by a leaf, so our keys find what to sign.
+pub fn psbt_from_template<C: Verification>(
+    secp: &Secp256k1<C>,
+    template: &Template,
+    prevouts: &[TemplatePrevout],
+    origins: &BTreeMap<XOnlyPublicKey, KeySource>,
+) -> Result<PartiallySignedTransaction, TemplateError> {
+    let mut tx = template.tx.clone();
+    if tx.input.len() != prevouts.len() {
+        return Err(TemplateError::WrongPrevoutCount {
+            expected: tx.input.len(),
+            found: prevouts.len(),
+        });
+    }
+    for (txin, prevout) in tx.input.iter_mut().zip(prevouts) {
+        txin.previous_output = prevout.outpoint;
+    }
+    let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx)
+        .expect("a template's transaction has no signatures");
+    for (index, (input, prevout)) in psbt.inputs.iter_mut().zip(prevouts).enumerate() {
+        input.witness_utxo = Some(prevout.utxo.clone());
+        let tr = match &prevout.descriptor {
+            None => continue,
+            Some(Descriptor::Tr(tr)) => tr,
+            Some(_) => return Err(TemplateError::NotTaproot { index }),
+        };
+        let scripts: Vec<_> = tr.iter_scripts().map(|(d, ms)| (d, ms.encode())).collect();
+        let info = if scripts.is_empty() {
+            TaprootSpendInfo::new_key_spend(secp, *tr.internal_key(), None)
+        } else {
+            let mut builder = TaprootBuilder::new();
+            for (depth, script) in &scripts {
+                builder = builder.add_leaf(*depth, script.clone())?;
+            }
+            builder.finalize(secp, *tr.internal_key())?
+        };
+        if Script::new_v1_p2tr_tweaked(info.output_key()) != prevout.utxo.script_pubkey {
+            return Err(TemplateError::DescriptorMismatch { index });
+        }
+        for (script, ver) in info.as_script_map().keys() {
+            let cb = info
+                .control_block(&(script.clone(), *ver))
+                .expect("every leaf has a control block");
+            input.tap_scripts.insert(cb, (script.clone(), *ver));
+        }
+        input.tap_internal_key = Some(info.internal_key());
+        input.tap_merkle_root = info.merkle_root();
+        for (pk, source) in origins {
+            let leaves: Vec<_> = info
+                .as_script_map()
+                .keys()
+                .filter(|(s, _)| pushes(s, &pk.serialize()))
+                .map(|(s, ver)| TapLeafHash::from_script(s, *ver))
+                .collect();
+            if !leaves.is_empty() || *pk == info.internal_key() {
+                input.tap_key_origins.insert(*pk, (leaves, source.clone()));
+            }
+        }
+    }
+    Ok(psbt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+    use bitcoin::util::amount::Amount;
+    use sapio::contract::*;
+    use sapio::sapio_base::Clause;
+    use sapio::*;
+    use std::sync::Arc;
+
+    /// pays `amount` to `to` once `key` signs
+    struct PayWithKey {
+        key: XOnlyPublicKey,
+        to: Compiled,
+        amount: Amount,
+    }
+    impl PayWithKey {
+        #[guard]
+        fn signed(self, _ctx: Context) {
+            Clause::Key(self.key)
+        }
+        #[then(guarded_by = "[Self::signed]")]
+        fn pay(self, ctx: Context) {
+            ctx.template()
+                .add_output(self.amount, &self.to, None)?
+                .into()
+        }
+    }
+    // `declare!` checks for a `nightly` feature this crate doesn't have
+    #[allow(unexpected_cfgs)]
+    impl Contract for PayWithKey {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn sign_compiled_template() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let (key, origin) = derive(&secp, &ours, "m/0");
+        let to = Compiled::from_address(
+            bitcoin::Address::p2tr(
+                &secp,
+                derive(&secp, &test_key(2), "m/0").0,
+                None,
+                Network::Regtest,
+            ),
+            None,
+        );
+        let contract = PayWithKey {
+            key,
+            to,
+            amount: Amount::from_sat(9_000),
+        };
+        let ctx = Context::new(
+            Network::Regtest,
+            Amount::from_sat(10_000),
+            Arc::new(sapio_ctv_emulator_trait::CTVAvailable),
+            "root".try_into().unwrap(),
+            Default::default(),
+        );
+        let compiled = contract.compile(ctx).unwrap();
+        let descriptor = match compiled.descriptor.clone() {
+            Some(sapio::contract::object::SupportedDescriptors::XOnly(d)) => d,
+            _ => panic!("sapio compiles to taproot"),
+        };
+        let template = compiled.ctv_to_tx.values().next().unwrap();
+        let prevout = TemplatePrevout {
+            outpoint: OutPoint::default(),
+            utxo: TxOut {
+                value: 10_000,
+                script_pubkey: compiled.address.clone().into(),
+            },
+            descriptor: Some(descriptor),
+        };
+        let origins = [(key, origin)].into_iter().collect();
+        let mut psbt =
+            psbt_from_template(&secp, template, std::slice::from_ref(&prevout), &origins).unwrap();
+        assert_eq!(psbt.unsigned_tx.output, template.tx.output);
+        assert!(!psbt.inputs[0].tap_scripts.is_empty());
+
+        let signed = ours
+            .sign_psbt_all_mut(&mut psbt, &secp, bitcoin::SchnorrSighashType::All)
+            .unwrap();
+        assert_eq!(signed, vec![0]);
+        assert!(!psbt.inputs[0].tap_script_sigs.is_empty());
+        verify_psbt(&psbt, &secp).unwrap();
+
+        let mut wrong = prevout;
+        wrong.utxo.script_pubkey = Script::new();
+        assert!(matches!(
+            psbt_from_template(&secp, template, &[wrong], &origins),
+            Err(TemplateError::DescriptorMismatch { index: 0 })
+        ));
+        assert!(matches!(
+            psbt_from_template(&secp, template, &[], &origins),
+            Err(TemplateError::WrongPrevoutCount {
+                expected: 1,
+                found: 0
+            })
+        ));
+    }
+}
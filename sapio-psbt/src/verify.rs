@@ -0,0 +1,346 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checking the taproot signatures in a PSBT, e.g. one returned by a
+//! cosigner, before relying on them.
+use super::*;
+use bitcoin::util::sighash::SighashCache;
+use miniscript::psbt::PsbtInputSatisfier;
+use miniscript::{Descriptor, DescriptorTrait};
+
+/// Errors from [`verify_psbt`] and [`verify_against_descriptor`]
+#[derive(Debug, Clone)]
+pub enum VerifyError {
+    /// the sighash for a signature could not be computed
+    Signing(PSBTSigningError),
+    /// input `index` has a `tap_key_sig` but does not spend a taproot output
+    NotTaproot { index: usize },
+    /// the `tap_key_sig` of input `index` does not verify for the output key
+    InvalidKeySig { index: usize, key: XOnlyPublicKey },
+    /// the signature by `key` for `leaf` does not verify
+    InvalidScriptSig {
+        index: usize,
+        key: XOnlyPublicKey,
+        leaf: TapLeafHash,
+    },
+    /// no input spends an output paying to the descriptor
+    NoInputForDescriptor,
+    /// the signatures of input `index` satisfy none of the descriptor's
+    /// spending conditions, or its timelocks are not met
+    Unsatisfied { index: usize },
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for VerifyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VerifyError::Signing(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<PSBTSigningError> for VerifyError {
+    fn from(e: PSBTSigningError) -> Self {
+        VerifyError::Signing(e)
+    }
+}
+
+/// Check every `tap_key_sig` and `tap_script_sigs` entry against the sighash
+/// it should commit to, returning the first which does not verify. Key path
+/// signatures are checked against the key in the `witness_utxo`, and script
+/// signatures assume no annex and no `OP_CODESEPARATOR`.
+pub fn verify_psbt<C: Verification>(
+    psbt: &PartiallySignedTransaction,
+    secp: &Secp256k1<C>,
+) -> Result<(), VerifyError> {
+    match signature_checks(psbt)?.iter().find(|c| !c.verifies(secp)) {
+        Some(check) => Err(check.failure.clone()),
+        None => Ok(()),
+    }
+}
+
+/// Same as [`verify_psbt`], but checks the signatures on rayon's thread
+/// pool, for PSBTs with many of them. The sighashes are still computed in
+/// order first, and the error is for the same signature [`verify_psbt`]
+/// would report.
+#[cfg(feature = "parallel")]
+pub fn verify_psbt_parallel<C: Verification>(
+    psbt: &PartiallySignedTransaction,
+    secp: &Secp256k1<C>,
+) -> Result<(), VerifyError> {
+    use rayon::prelude::*;
+    match signature_checks(psbt)?
+        .par_iter()
+        .find_first(|c| !c.verifies(secp))
+    {
+        Some(check) => Err(check.failure.clone()),
+        None => Ok(()),
+    }
+}
+
+/// Check that every input spending an output paying to `descriptor` is
+/// signed well enough to spend it: its signatures verify, as in
+/// [`verify_psbt`], and satisfy one of the descriptor's spending conditions,
+/// such as every key of an `and` leaf, with the transaction meeting any
+/// timelock on the way. Returns the indices of those inputs.
+pub fn verify_against_descriptor<C: Verification>(
+    psbt: &PartiallySignedTransaction,
+    secp: &Secp256k1<C>,
+    descriptor: &Descriptor<XOnlyPublicKey>,
+) -> Result<Vec<usize>, VerifyError> {
+    verify_psbt(psbt, secp)?;
+    let script_pubkey = descriptor.script_pubkey();
+    let utxos = witness_utxos(psbt)?;
+    let inputs: Vec<usize> = (0..psbt.inputs.len())
+        .filter(|&index| utxos[index].script_pubkey == script_pubkey)
+        .collect();
+    if inputs.is_empty() {
+        return Err(VerifyError::NoInputForDescriptor);
+    }
+    for &index in &inputs {
+        descriptor
+            .get_satisfaction(PsbtInputSatisfier::new(psbt, index))
+            .map_err(|_| VerifyError::Unsatisfied { index })?;
+    }
+    Ok(inputs)
+}
+
+/// A signature, what it must verify against, and the error if it does not
+struct SignatureCheck {
+    msg: bitcoin::secp256k1::Message,
+    sig: bitcoin::secp256k1::schnorr::Signature,
+    key: XOnlyPublicKey,
+    failure: VerifyError,
+}
+
+impl SignatureCheck {
+    fn verifies<C: Verification>(&self, secp: &Secp256k1<C>) -> bool {
+        secp.verify_schnorr(&self.sig, &self.msg, &self.key).is_ok()
+    }
+}
+
+/// Every signature in the PSBT, in input order with each input's key path
+/// signature first
+fn signature_checks(psbt: &PartiallySignedTransaction) -> Result<Vec<SignatureCheck>, VerifyError> {
+    check_psbt(psbt)?;
+    let mut checks = vec![];
+    if psbt
+        .inputs
+        .iter()
+        .all(|i| i.tap_key_sig.is_none() && i.tap_script_sigs.is_empty())
+    {
+        return Ok(checks);
+    }
+    let utxos = witness_utxos(psbt)?;
+    let prevouts = Prevouts::All(&utxos);
+    let tx = psbt.clone().extract_tx();
+    let mut sighash = SighashCache::new(&tx);
+    for (index, input) in psbt.inputs.iter().enumerate() {
+        if let Some(sig) = input.tap_key_sig {
+            let script = &utxos[index].script_pubkey;
+            let key = match script.is_v1_p2tr() {
+                true => XOnlyPublicKey::from_slice(&script[2..]).ok(),
+                false => None,
+            }
+            .ok_or(VerifyError::NotTaproot { index })?;
+            let hash = sighash
+                .taproot_signature_hash(index, &prevouts, None, None, sig.hash_ty)
+                .map_err(PSBTSigningError::Sighash)?;
+            checks.push(SignatureCheck {
+                msg: crate::sighash_message(&hash),
+                sig: sig.sig,
+                key,
+                failure: VerifyError::InvalidKeySig { index, key },
+            });
+        }
+        for (&(key, leaf), sig) in &input.tap_script_sigs {
+            let hash = sighash
+                .taproot_signature_hash(
+                    index,
+                    &prevouts,
+                    None,
+                    Some((leaf, DEFAULT_CODESEP)),
+                    sig.hash_ty,
+                )
+                .map_err(PSBTSigningError::Sighash)?;
+            checks.push(SignatureCheck {
+                msg: crate::sighash_message(&hash),
+                sig: sig.sig,
+                key,
+                failure: VerifyError::InvalidScriptSig { index, key, leaf },
+            });
+        }
+    }
+    Ok(checks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    fn corrupt(sig: &mut SchnorrSig) {
+        let mut bytes = *sig.sig.as_ref();
+        bytes[63] ^= 1;
+        sig.sig = bitcoin::secp256k1::schnorr::Signature::from_slice(&bytes).unwrap();
+    }
+
+    #[test]
+    fn good_and_corrupted_signatures() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let (pk, origin) = derive(&secp, &ours, "m/1");
+        let mut psbt = unsigned_psbt(2);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &ours, "m/0"), &[(pk, origin)]);
+        psbt.inputs[1] = taproot_input(&secp, &derive(&secp, &ours, "m/2"), &[]);
+        verify_psbt(&psbt, &secp).unwrap();
+        ours.sign_psbt_mut(&mut psbt, &secp, bitcoin::SchnorrSighashType::All)
+            .unwrap();
+        verify_psbt(&psbt, &secp).unwrap();
+
+        let mut bad = psbt.clone();
+        corrupt(bad.inputs[1].tap_key_sig.as_mut().unwrap());
+        let script = &psbt.inputs[1].witness_utxo.as_ref().unwrap().script_pubkey;
+        let output_key = XOnlyPublicKey::from_slice(&script[2..]).unwrap();
+        assert!(matches!(
+            verify_psbt(&bad, &secp),
+            Err(VerifyError::InvalidKeySig { index: 1, key }) if key == output_key
+        ));
+
+        let mut bad = psbt;
+        let (&(_, leaf), sig) = bad.inputs[0].tap_script_sigs.iter_mut().next().unwrap();
+        corrupt(sig);
+        assert!(matches!(
+            verify_psbt(&bad, &secp),
+            Err(VerifyError::InvalidScriptSig { index: 0, key, leaf: l }) if key == pk && l == leaf
+        ));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_finds_first_bad_signature() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let mut psbt = unsigned_psbt(10);
+        for (i, input) in psbt.inputs.iter_mut().enumerate() {
+            let leaf_key = derive(&secp, &ours, &format!("m/1/{}", i));
+            *input = taproot_input(
+                &secp,
+                &derive(&secp, &ours, &format!("m/0/{}", i)),
+                &[leaf_key],
+            );
+        }
+        verify_psbt_parallel(&psbt, &secp).unwrap();
+        ours.sign_psbt_mut(&mut psbt, &secp, bitcoin::SchnorrSighashType::All)
+            .unwrap();
+        verify_psbt_parallel(&psbt, &secp).unwrap();
+
+        let mut bad = psbt.clone();
+        corrupt(bad.inputs[7].tap_key_sig.as_mut().unwrap());
+        for sig in bad.inputs[4].tap_script_sigs.values_mut() {
+            corrupt(sig);
+        }
+        assert!(matches!(
+            verify_psbt_parallel(&bad, &secp),
+            Err(VerifyError::InvalidScriptSig { index: 4, .. })
+        ));
+        assert!(matches!(
+            verify_psbt(&bad, &secp),
+            Err(VerifyError::InvalidScriptSig { index: 4, .. })
+        ));
+        bad.inputs[4] = psbt.inputs[4].clone();
+        assert!(matches!(
+            verify_psbt_parallel(&bad, &secp),
+            Err(VerifyError::InvalidKeySig { index: 7, .. })
+        ));
+    }
+
+    #[test]
+    fn satisfies_descriptor_branch() {
+        use bitcoin::util::taproot::LeafVersion;
+        use miniscript::ToPublicKey;
+        use std::str::FromStr;
+        let secp = Secp256k1::new();
+        let (alice, bob) = (test_key(1), test_key(3));
+        let internal = derive(&secp, &test_key(2), "m/0");
+        let a = derive(&secp, &alice, "m/1");
+        let b = derive(&secp, &bob, "m/1");
+        let c = derive(&secp, &test_key(4), "m/1");
+        let descriptor = Descriptor::<XOnlyPublicKey>::from_str(&format!(
+            "tr({},{{and_v(v:pk({}),pk({})),pk({})}})",
+            internal.0, a.0, b.0, c.0
+        ))
+        .unwrap();
+        let tr = match &descriptor {
+            Descriptor::Tr(tr) => tr,
+            _ => unreachable!(),
+        };
+        let info = tr.spend_info();
+        let mut input = bitcoin::psbt::Input {
+            witness_utxo: Some(TxOut {
+                value: 10_000,
+                script_pubkey: descriptor.script_pubkey(),
+            }),
+            tap_internal_key: Some(internal.0),
+            tap_merkle_root: info.merkle_root(),
+            ..Default::default()
+        };
+        for (_, ms) in tr.iter_scripts() {
+            let script = ms.encode();
+            let cb = info
+                .control_block(&(script.clone(), LeafVersion::TapScript))
+                .unwrap();
+            let leaf = TapLeafHash::from_script(&script, LeafVersion::TapScript);
+            for (pk, origin) in [&a, &b, &c] {
+                if ms.iter_pk().any(|k| k.to_x_only_pubkey() == *pk) {
+                    input
+                        .tap_key_origins
+                        .insert(*pk, (vec![leaf], origin.clone()));
+                }
+            }
+            input
+                .tap_scripts
+                .insert(cb, (script, LeafVersion::TapScript));
+        }
+        let mut psbt = unsigned_psbt(2);
+        psbt.inputs[0] = input;
+        psbt.inputs[1] = taproot_input(&secp, &derive(&secp, &alice, "m/9"), &[]);
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+
+        assert!(matches!(
+            verify_against_descriptor(&psbt, &secp, &descriptor),
+            Err(VerifyError::Unsatisfied { index: 0 })
+        ));
+        // one of the `and` branch's keys is not enough
+        alice.sign_psbt_mut(&mut psbt, &secp, hash_ty).unwrap();
+        assert!(psbt.inputs[1].tap_key_sig.is_some());
+        assert!(matches!(
+            verify_against_descriptor(&psbt, &secp, &descriptor),
+            Err(VerifyError::Unsatisfied { index: 0 })
+        ));
+        bob.sign_psbt_mut(&mut psbt, &secp, hash_ty).unwrap();
+        assert_eq!(psbt.inputs[0].tap_script_sigs.len(), 2);
+        assert_eq!(
+            verify_against_descriptor(&psbt, &secp, &descriptor).unwrap(),
+            vec![0]
+        );
+
+        let other = Descriptor::<XOnlyPublicKey>::from_str(&format!("tr({})", c.0)).unwrap();
+        assert!(matches!(
+            verify_against_descriptor(&psbt, &secp, &other),
+            Err(VerifyError::NoInputForDescriptor)
+        ));
+        corrupt(psbt.inputs[0].tap_script_sigs.values_mut().next().unwrap());
+        assert!(matches!(
+            verify_against_descriptor(&psbt, &secp, &descriptor),
+            Err(VerifyError::InvalidScriptSig { index: 0, .. })
+        ));
+    }
+}
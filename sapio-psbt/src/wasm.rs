@@ -0,0 +1,77 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A synchronous, filesystem free entry point for browser tooling. Build with
+//! `default-features = false` for `wasm32-unknown-unknown`, which drops the
+//! tokio helpers and everything needing OS randomness.
+use super::*;
+
+/// Errors from [`sign_bytes`]
+#[derive(Debug)]
+pub enum SignBytesError {
+    Key(KeyError),
+    Signing(PSBTSigningError),
+}
+
+impl Display for SignBytesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for SignBytesError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SignBytesError::Key(e) => Some(e),
+            SignBytesError::Signing(e) => Some(e),
+        }
+    }
+}
+
+/// Sign a serialized PSBT with a plaintext key, in either the raw or base58
+/// form, returning the serialized signed PSBT
+pub fn sign_bytes(
+    key_bytes: &[u8],
+    psbt_bytes: &[u8],
+    hash_ty: bitcoin::SchnorrSighashType,
+) -> Result<Vec<u8>, SignBytesError> {
+    let key = SigningKey::read_plaintext_key(key_bytes).map_err(SignBytesError::Key)?;
+    let psbt = deserialize(psbt_bytes)
+        .map_err(|e| SignBytesError::Signing(PSBTSigningError::InvalidPSBT(e.to_string())))?;
+    key.sign(psbt, hash_ty).map_err(SignBytesError::Signing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    #[test]
+    fn sign_serialized() {
+        let secp = Secp256k1::new();
+        let ours = test_key(1);
+        let mut psbt = unsigned_psbt(1);
+        psbt.inputs[0] = taproot_input(&secp, &derive(&secp, &ours, "m/0"), &[]);
+        let hash_ty = bitcoin::SchnorrSighashType::All;
+        let bytes = serialize(&psbt);
+
+        let signed = sign_bytes(&ours.0[0].encode(), &bytes, hash_ty).unwrap();
+        assert_eq!(signed, ours.sign(psbt, hash_ty).unwrap());
+        let base58 = ours.0[0].to_string();
+        assert_eq!(
+            sign_bytes(base58.as_bytes(), &bytes, hash_ty).unwrap(),
+            signed
+        );
+
+        assert!(matches!(
+            sign_bytes(&[0; 10], &bytes, hash_ty),
+            Err(SignBytesError::Key(_))
+        ));
+        assert!(matches!(
+            sign_bytes(&ours.0[0].encode(), &bytes[1..], hash_ty),
+            Err(SignBytesError::Signing(PSBTSigningError::InvalidPSBT(_)))
+        ));
+    }
+}